@@ -31,10 +31,57 @@ in
         example = literalExpression ''5'';
         description = lib.mdDoc ''The number of generations to keep when rebuilding the system. Leaving as null or setting to 0 will keep all generations.'';
       };
+      dbmirror = mkOption {
+        type = with types; nullOr str;
+        default = null;
+        example = literalExpression ''"file:///srv/mirror/nix-data-db"'';
+        description = lib.mdDoc ''Base URL of the nix-data-db mirror. Leaving as null uses `https://raw.githubusercontent.com/snowflakelinux/nix-data-db/main`. All mirror options accept `file://` URLs.'';
+      };
+      versionmirror = mkOption {
+        type = with types; nullOr str;
+        default = null;
+        example = literalExpression ''"https://mirror.example.com/nixpkgs-version-data"'';
+        description = lib.mdDoc ''Base URL of the nixpkgs-version-data mirror. Leaving as null uses `https://raw.githubusercontent.com/snowflakelinux/nixpkgs-version-data/main`.'';
+      };
+      channelmirror = mkOption {
+        type = with types; nullOr str;
+        default = null;
+        example = literalExpression ''"https://mirror.example.com/channels"'';
+        description = lib.mdDoc ''Base URL of the NixOS channels mirror. Leaving as null uses `https://channels.nixos.org`.'';
+      };
+      releasemirror = mkOption {
+        type = with types; nullOr str;
+        default = null;
+        example = literalExpression ''"https://mirror.example.com/releases"'';
+        description = lib.mdDoc ''Base URL of the NixOS releases mirror. Leaving as null uses `https://releases.nixos.org`.'';
+      };
+      requirechecksum = mkOption {
+        type = with types; nullOr bool;
+        default = null;
+        example = literalExpression ''true'';
+        description = lib.mdDoc ''Whether downloaded databases must have a checksum in the `.sha256` manifest next to their `.ver` file on the mirror. Leaving as null only verifies checksums that the mirror provides. Only downloads from the `dbmirror` and `versionmirror` are verified; NixOS options and legacy channel packages from the `channelmirror` and `releasemirror` are not.'';
+      };
+      publickey = mkOption {
+        type = with types; nullOr str;
+        default = null;
+        example = literalExpression ''"RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3"'';
+        description = lib.mdDoc ''Minisign public key used to verify the `.minisig` signatures of downloaded databases. Downloads from the `dbmirror` and `versionmirror` without a valid signature are rejected. Downloads from the `channelmirror` and `releasemirror` are not verified.'';
+      };
+      cachepolicy = mkOption {
+        type = with types; nullOr (either (enum [ "alwaysrefresh" "offlineonly" ]) (submodule {
+          options.refreshifolderthan = mkOption {
+            type = ints.unsigned;
+            description = lib.mdDoc ''Number of seconds after which cached data is checked for a newer version.'';
+          };
+        }));
+        default = null;
+        example = literalExpression ''{ refreshifolderthan = 86400; }'';
+        description = lib.mdDoc ''When cached package and option data is refreshed. `"alwaysrefresh"` checks for a newer version on every use, `"offlineonly"` never accesses the network. Leaving as null uses `"alwaysrefresh"`.'';
+      };
     };
   };
 
   config = mkIf cfg.enable {
-      environment.etc."nix-data/config.json".source = jsonFormat.generate "config.json" { inherit (cfg) systemconfig flake flakearg generations; };
+      environment.etc."nix-data/config.json".source = jsonFormat.generate "config.json" { inherit (cfg) systemconfig flake flakearg generations dbmirror versionmirror channelmirror releasemirror requirechecksum publickey cachepolicy; };
     };
 }
@@ -0,0 +1,78 @@
+use crate::Result;
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    process,
+};
+
+/// A file that is written to a temporary path next to its destination
+/// and only moved into place once it is complete.
+/// The temporary file is removed if the `AtomicFile` is dropped without being committed.
+pub(crate) struct AtomicFile {
+    path: PathBuf,
+    tmppath: PathBuf,
+    committed: bool,
+}
+
+impl AtomicFile {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut tmppath = path.clone().into_os_string();
+        tmppath.push(format!(".tmp{}", process::id()));
+        let tmppath = PathBuf::from(tmppath);
+        // Left over from an interrupted write
+        if tmppath.exists() {
+            fs::remove_file(&tmppath)?;
+        }
+        Ok(AtomicFile {
+            path,
+            tmppath,
+            committed: false,
+        })
+    }
+
+    /// Path the contents should be written to before committing.
+    pub fn tmppath(&self) -> &Path {
+        &self.tmppath
+    }
+
+    /// Creates the temporary file for writing.
+    pub fn create(&self) -> Result<File> {
+        Ok(File::create(&self.tmppath)?)
+    }
+
+    /// Flushes the temporary file to disk and renames it to its destination.
+    pub fn commit(mut self) -> Result<()> {
+        File::open(&self.tmppath)?.sync_all()?;
+        fs::rename(&self.tmppath, &self.path)?;
+        self.committed = true;
+        syncdir(&self.path)
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.tmppath);
+        }
+    }
+}
+
+/// Makes a rename in the parent directory of `path` durable.
+fn syncdir(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Commits `data` together with a version stamp at `verpath`.
+/// The data is moved into place first, so an interrupted commit leaves a stale version
+/// that doesn't match the data and causes it to be fetched again.
+pub(crate) fn commitversioned(data: AtomicFile, verpath: &str, version: &str) -> Result<()> {
+    let ver = AtomicFile::new(verpath)?;
+    ver.create()?.write_all(version.as_bytes())?;
+    data.commit()?;
+    ver.commit()
+}
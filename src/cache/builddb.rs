@@ -0,0 +1,122 @@
+use crate::{Error, Result};
+use log::debug;
+use sqlx::{
+    query::Query,
+    sqlite::{SqliteArguments, SqliteConnectOptions, SqliteJournalMode, SqliteSynchronous},
+    Connection, Sqlite, SqliteConnection,
+};
+use std::{
+    fs,
+    future::Future,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use super::{
+    atomic::{self, AtomicFile},
+    lock::CacheLock,
+    progress::{self, Phase},
+    CachedFile,
+};
+
+/// An `INSERT` statement with values bound to it.
+pub(crate) type Insert<'q> = Query<'q, Sqlite, SqliteArguments<'q>>;
+
+/// Returns the path to a database derived from the cached file `source`, such as a search index,
+/// building it first if needed.
+/// The database is stored next to `source` as `{stem}_{suffix}.db` and rebuilt with `build`
+/// whenever `source` changes. `build` is called with the name of the database and the path to write it to.
+pub(crate) async fn derived<F, Fut>(
+    source: &CachedFile,
+    ext: &str,
+    suffix: &str,
+    build: F,
+) -> Result<String>
+where
+    F: FnOnce(String, PathBuf) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let stem = source
+        .path
+        .strip_suffix(&format!(".{}", ext))
+        .ok_or_else(|| Error::Cache(format!("{} is not a .{} file", source.path, ext)))?;
+    let path = format!("{}_{}.db", stem, suffix);
+    let verpath = format!("{}_{}.ver", stem, suffix);
+    let name = Path::new(&path)
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    // Identifies the contents of the source file
+    let modified = fs::metadata(&source.path)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let version = format!(
+        "{}@{}",
+        source.version.as_deref().unwrap_or_default(),
+        modified
+    );
+
+    let _lock = CacheLock::acquire(&name).await?;
+    let current = fs::read_to_string(&verpath).ok();
+    if !Path::new(&path).exists() || current.as_deref() != Some(&version) {
+        debug!("Building {}", path);
+        let out = AtomicFile::new(&path)?;
+        build(name.to_string(), out.tmppath().to_path_buf()).await?;
+        atomic::commitversioned(out, &verpath, &version)?;
+        progress::report(&name, Phase::Done, 0, None);
+    }
+    Ok(path)
+}
+
+/// Creates a new database at `dbfile` that is only written once.
+pub(crate) async fn create(dbfile: &Path) -> Result<SqliteConnection> {
+    // The file is only moved into place once it is complete and synced, so durability isn't needed here
+    let options = SqliteConnectOptions::new()
+        .filename(dbfile)
+        .create_if_missing(true)
+        .journal_mode(SqliteJournalMode::Memory)
+        .synchronous(SqliteSynchronous::Off);
+    Ok(SqliteConnection::connect_with(&options).await?)
+}
+
+/// Inserts `rows` into the `columns` of `table`, several rows per statement,
+/// and reports the progress as importing `entry`. `bind` binds the values of a row in the order of `columns`.
+pub(crate) async fn insert<T, F>(
+    conn: &mut SqliteConnection,
+    entry: &str,
+    table: &str,
+    columns: &[&str],
+    rows: &[T],
+    bind: F,
+) -> Result<()>
+where
+    F: for<'q> Fn(Insert<'q>, &'q T) -> Result<Insert<'q>>,
+{
+    // Rows per statement, keeping below SQLite's limit of 999 parameters
+    let batch = 999 / columns.len();
+    let row = format!("({})", vec!["?"; columns.len()].join(", "));
+
+    let total = rows.len() as u64;
+    progress::report(entry, Phase::Importing, 0, Some(total));
+    let mut done = 0;
+    for chunk in rows.chunks(batch) {
+        // Full batches share the same statement, so it is only prepared once
+        let sql = format!(
+            "INSERT OR IGNORE INTO {} ({}) VALUES {}",
+            table,
+            columns.join(", "),
+            vec![row.as_str(); chunk.len()].join(", ")
+        );
+        let mut query = sqlx::query(&sql);
+        for row in chunk {
+            query = bind(query, row)?;
+        }
+        query.execute(&mut *conn).await?;
+        done += chunk.len() as u64;
+        progress::report(entry, Phase::Importing, done, Some(total));
+    }
+    Ok(())
+}
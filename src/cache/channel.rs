@@ -1,120 +1,64 @@
-use crate::CACHEDIR;
-use anyhow::{anyhow, Context, Result};
-use log::info;
+use crate::{context::cachedir, storepath::StorePath, utils, Error, Result};
+use log::debug;
 use serde::Deserialize;
-use sqlx::SqlitePool;
 use std::{
-    collections::{HashMap, HashSet},
-    fs::{self, File},
-    io::{BufReader, Read, Write},
-    path::Path,
+    collections::{BTreeSet, HashMap, HashSet},
+    fs,
     process::Command,
 };
 
 use super::{
-    nixos::{self, getnixospkgs, nixospkgs},
-    NixPkgList,
+    db::{self, PackageDb},
+    entry::{CacheEntry, Format, Release, Source, Version},
+    mirror,
+    nixos::{self, getnixospkgs},
+    CachedFile,
 };
 
 /// Gets a list of all packages in legacy NixOS systems with their name and version.
 /// Can be used to find what versions of system packages are currently installed.
 /// Will only work on legacy NixOS systems.
-pub async fn legacypkgs() -> Result<String> {
-    let versionout = Command::new("nixos-version").arg("--json").output()?;
-    let version: HashMap<String, String> = serde_json::from_slice(&versionout.stdout)?;
-
+pub async fn legacypkgs() -> Result<CachedFile> {
+    let version = utils::nixosversion()?;
     let nixosversion = version
         .get("nixosVersion")
-        .context("No NixOS version found")?;
-    let relver = if nixosversion[5..8].eq("pre") {
+        .ok_or_else(|| Error::Parse(String::from("No NixOS version found")))?;
+    let relver = if nixosversion.get(5..8) == Some("pre") {
         "unstable"
     } else {
-        &nixosversion[0..5]
+        utils::nixosrelease(&version)?
     };
 
-    // If cache directory doesn't exist, create it
-    if !std::path::Path::new(&*CACHEDIR).exists() {
-        std::fs::create_dir_all(&*CACHEDIR)?;
-    }
-
-    // Check if latest version is already downloaded
-    if let Ok(prevver) = fs::read_to_string(&format!("{}/legacypkgs.ver", &*CACHEDIR)) {
-        if prevver.eq(nixosversion) && Path::new(&format!("{}/legacypkgs.db", &*CACHEDIR)).exists()
-        {
-            info!("No new version of NixOS legacy found");
-            return Ok(format!("{}/legacypkgs.db", &*CACHEDIR));
+    let mirrors = mirror::mirrors()?;
+    let mut sources = vec![];
+    if let Some(rev) = version.get("nixpkgsRevision") {
+        for channel in [relver, "unstable"] {
+            sources.push(Source::Download {
+                url: format!("{}/nixos-{}/{}.json.br", mirrors.versiondata, channel, rev),
+                format: Format::Versions,
+                verify: true,
+            });
         }
     }
+    sources.push(Source::Download {
+        url: format!(
+            "{}/nixos/{}/nixos-{}/packages.json.br",
+            mirrors.releases, relver, nixosversion
+        ),
+        format: Format::Packages,
+        verify: false,
+    });
 
-    async fn downloadrelease(relver: &str, nixosversion: &str) -> Result<HashMap<String, String>> {
-        let url = format!(
-            "https://releases.nixos.org/nixos/{}/nixos-{}/packages.json.br",
-            relver, nixosversion
-        );
-        // Download file with reqwest
-        let client = reqwest::Client::builder().brotli(true).build()?;
-        let resp = client.get(url).send().await;
-        let resp = if let Ok(r) = resp {
-            r
-        } else {
-            return Err(anyhow!("Failed to download legacy packages.json"));
-        };
-        if resp.status().is_success() {
-            let pkgjson: NixPkgList =
-                serde_json::from_reader(BufReader::new(resp.text().await?.as_bytes()))?;
-            let pkgout = pkgjson
-                .packages
-                .iter()
-                .map(|(k, v)| (k.to_string(), v.version.to_string()))
-                .collect::<HashMap<String, String>>();
-            Ok(pkgout)
-        } else {
-            Err(anyhow!("Failed to download legacy packages.json"))
-        }
+    CacheEntry {
+        name: "legacypkgs",
+        ext: "db",
+        releases: vec![Release {
+            version: Version::Known(nixosversion.to_string()),
+            sources,
+        }],
     }
-
-    // Get list of packages
-    let pkgout = if let Some(rev) = version.get("nixpkgsRevision") {
-        let url = format!("https://raw.githubusercontent.com/snowflakelinux/nixpkgs-version-data/main/nixos-{}/{}.json.br", relver, rev);
-        println!("{}", url);
-        let resp = reqwest::get(&url).await?;
-        if resp.status().is_success() {
-            let r = resp.bytes().await?;
-            println!("Downloaded");
-            let mut br = brotli::Decompressor::new(r.as_ref(), 4096);
-            let mut pkgsout = Vec::new();
-            br.read_to_end(&mut pkgsout)?;
-            let pkgsjson: HashMap<String, String> = serde_json::from_slice(&pkgsout)?;
-            println!("Decompressed");
-            pkgsjson
-        } else {
-            let url = format!("https://raw.githubusercontent.com/snowflakelinux/nixpkgs-version-data/main/nixos-unstable/{}.json.br", rev);
-            println!("{}", url);
-            let resp = reqwest::get(&url).await?;
-            if resp.status().is_success() {
-                let r = resp.bytes().await?;
-                println!("Downloaded");
-                let mut br = brotli::Decompressor::new(r.as_ref(), 4096);
-                let mut pkgsout = Vec::new();
-                br.read_to_end(&mut pkgsout)?;
-                let pkgsjson: HashMap<String, String> = serde_json::from_slice(&pkgsout)?;
-                println!("Decompressed");
-                pkgsjson
-            } else {
-                downloadrelease(relver, nixosversion).await?
-            }
-        }
-    } else {
-        downloadrelease(relver, nixosversion).await?
-    };
-    let dbfile = format!("{}/legacypkgs.db", &*CACHEDIR);
-
-    nixos::createdb(&dbfile, &pkgout).await?;
-
-    // Write version downloaded to file
-    File::create(format!("{}/legacypkgs.ver", &*CACHEDIR))?.write_all(nixosversion.as_bytes())?;
-
-    Ok(format!("{}/legacypkgs.db", &*CACHEDIR))
+    .get()
+    .await
 }
 
 /// Gets a list of all packages in NixOS systems with their attribute and version.
@@ -127,13 +71,61 @@ pub async fn getlegacypkgs(paths: &[&str]) -> Result<HashMap<String, String>> {
 struct EnvPkgOut {
     pname: String,
     version: String,
+    #[serde(rename = "outputName")]
+    outputname: Option<String>,
+    #[serde(default)]
+    outputs: HashMap<String, Option<String>>,
+}
+
+/// How an attribute was matched to a package installed with `nix-env`, from most to least certain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Confidence {
+    /// The attribute evaluates to the installed store path.
+    StorePath,
+    /// The attribute is the only one with the package's `pname` and version.
+    Version,
+    /// The attribute is the only one with the package's `pname`, but its version differs from the installed one.
+    Name,
+}
+
+/// The attribute of a package installed with `nix-env`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolution {
+    Resolved {
+        attribute: String,
+        confidence: Confidence,
+    },
+    /// Several attributes match equally well, the most likely first.
+    Ambiguous(Vec<String>),
+    /// No attribute in the package database has the package's `pname`.
+    NotFound,
+}
+
+impl Resolution {
+    /// The resolved attribute, if there is exactly one.
+    pub fn attribute(&self) -> Option<&str> {
+        match self {
+            Resolution::Resolved { attribute, .. } => Some(attribute),
+            _ => None,
+        }
+    }
+}
+
+/// A package installed with `nix-env`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvPkg {
+    pub pname: String,
+    pub version: String,
+    /// Store path of the installed output, if `nix-env` reports one.
+    pub storepath: Option<StorePath>,
+    pub resolution: Resolution,
 }
 
 /// Gets a list of all packages installed with `nix-env` with their name and version.
 /// Due to limitations of `nix-env`, the HashMap keys are the packages `pname` rather than `attributePath`.
-/// This means that finding more information about the specific derivations is more difficult.
+/// Use [getenvpkgs_resolved()] to find the attributes.
 pub fn getenvpkgs() -> Result<HashMap<String, String>> {
-    let output = Command::new("nix-env").arg("-q").arg("--json").output()?;
+    let output = utils::output(Command::new("nix-env").arg("-q").arg("--json"))?;
     let pkgs: HashMap<String, EnvPkgOut> = serde_json::from_slice(&output.stdout)?;
     let mut out = HashMap::new();
     for (_, v) in pkgs {
@@ -142,9 +134,187 @@ pub fn getenvpkgs() -> Result<HashMap<String, String>> {
     Ok(out)
 }
 
+/// Gets all packages installed with `nix-env` along with their attribute,
+/// keyed by the name `nix-env -q --json` reports for each installed element,
+/// so several installed versions of the same `pname` are all included.
+///
+/// Candidates are the attributes with the same `pname` in the package database for the current system,
+/// narrowed down to those with the installed version if there are any.
+/// The candidates are then evaluated from `<nixpkgs>` and compared to the installed store path.
+/// If that isn't possible, such as when `<nixpkgs>` is not in the `NIX_PATH`,
+/// a single remaining candidate is resolved with a lower [Confidence] and several are reported as [Resolution::Ambiguous].
+///
+/// # Example
+/// ```no_run
+/// use nix_data::cache::channel::Resolution;
+///
+/// # async fn example() -> nix_data::Result<()> {
+/// for pkg in nix_data::cache::channel::getenvpkgs_resolved().await?.into_values() {
+///     match pkg.resolution {
+///         Resolution::Resolved { attribute, confidence } => println!("{}: {} ({:?})", pkg.pname, attribute, confidence),
+///         Resolution::Ambiguous(attributes) => println!("{}: one of {}", pkg.pname, attributes.join(", ")),
+///         Resolution::NotFound => println!("{}: not found", pkg.pname),
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub async fn getenvpkgs_resolved() -> Result<HashMap<String, EnvPkg>> {
+    let output = utils::output(
+        Command::new("nix-env")
+            .arg("-q")
+            .arg("--json")
+            .arg("--out-path"),
+    )?;
+    let pkgs: HashMap<String, EnvPkgOut> = serde_json::from_slice(&output.stdout)?;
+
+    let db = PackageDb::open(db::systempkgs().await?).await?;
+    let candidates = db
+        .allbypname(&pkgs.values().map(|p| &p.pname).collect::<Vec<_>>())
+        .await?;
+
+    // Candidates for each package, and whether they have the installed version
+    let mut matched = HashMap::new();
+    for (name, pkg) in &pkgs {
+        let all = candidates.get(&pkg.pname).cloned().unwrap_or_default();
+        let sameversion = all
+            .iter()
+            .filter(|(_, version)| version == &pkg.version)
+            .map(|(attribute, _)| attribute.to_string())
+            .collect::<Vec<_>>();
+        let candidates = if sameversion.is_empty() {
+            (
+                all.into_iter().map(|(attribute, _)| attribute).collect(),
+                false,
+            )
+        } else {
+            (sameversion, true)
+        };
+        matched.insert(name.to_string(), candidates);
+    }
+
+    let outpaths = outpaths(
+        &matched
+            .values()
+            .flat_map(|(attributes, _)| attributes)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>(),
+    );
+
+    let mut out = HashMap::new();
+    for (name, pkg) in pkgs {
+        let storepath = pkg
+            .outputs
+            .get(pkg.outputname.as_deref().unwrap_or("out"))
+            .and_then(|p| p.as_deref())
+            .and_then(|p| StorePath::parse(p).ok());
+        let (candidates, sameversion) = matched.remove(&name).unwrap_or_default();
+        let resolution = resolve(candidates, sameversion, storepath.as_ref(), &outpaths);
+        debug!("getenvpkgs_resolved: {} -> {:?}", name, resolution);
+        out.insert(
+            name,
+            EnvPkg {
+                pname: pkg.pname,
+                version: pkg.version,
+                storepath,
+                resolution,
+            },
+        );
+    }
+    Ok(out)
+}
+
+/// Picks the attribute for a package from `candidates`, which are ordered from most to least likely.
+fn resolve(
+    candidates: Vec<String>,
+    sameversion: bool,
+    storepath: Option<&StorePath>,
+    outpaths: &HashMap<String, HashSet<String>>,
+) -> Resolution {
+    if let Some(storepath) = storepath.map(|p| p.to_string()) {
+        // Attributes building the same store path are interchangeable, so the most likely one is used
+        if let Some(attribute) = candidates.iter().find(|attribute| {
+            outpaths
+                .get(*attribute)
+                .map(|paths| paths.contains(&storepath))
+                .unwrap_or(false)
+        }) {
+            return Resolution::Resolved {
+                attribute: attribute.to_string(),
+                confidence: Confidence::StorePath,
+            };
+        }
+    }
+    match candidates.len() {
+        0 => Resolution::NotFound,
+        1 => Resolution::Resolved {
+            attribute: candidates.into_iter().next().unwrap_or_default(),
+            confidence: if sameversion {
+                Confidence::Version
+            } else {
+                Confidence::Name
+            },
+        },
+        _ => Resolution::Ambiguous(candidates),
+    }
+}
+
+/// Evaluates the output paths of `attributes` from `<nixpkgs>`.
+/// Attributes that fail to evaluate are left out.
+fn outpaths(attributes: &[&String]) -> HashMap<String, HashSet<String>> {
+    #[derive(Deserialize)]
+    struct OutPathsOut {
+        #[serde(default)]
+        outputs: HashMap<String, Option<String>>,
+    }
+
+    fn query(attributes: &[&String]) -> Option<HashMap<String, HashSet<String>>> {
+        let output = utils::output(
+            Command::new("nix-env")
+                .arg("-f")
+                .arg("<nixpkgs>")
+                .arg("-qa")
+                .arg("--json")
+                .arg("--out-path")
+                .arg("-A")
+                .args(attributes),
+        )
+        .ok()
+        .filter(|o| o.status.success())?;
+        let pkgs: HashMap<String, OutPathsOut> = serde_json::from_slice(&output.stdout).ok()?;
+        Some(
+            pkgs.into_iter()
+                .map(|(attribute, pkg)| (attribute, pkg.outputs.into_values().flatten().collect()))
+                .collect(),
+        )
+    }
+
+    // One broken or unfree attribute fails the whole query, so split failing queries in half
+    // until the attributes that fail are found
+    fn bisect(attributes: &[&String]) -> HashMap<String, HashSet<String>> {
+        if attributes.is_empty() {
+            return HashMap::new();
+        }
+        if let Some(out) = query(attributes) {
+            return out;
+        }
+        if attributes.len() == 1 {
+            debug!("outpaths: failed to evaluate {}", attributes[0]);
+            return HashMap::new();
+        }
+        let (left, right) = attributes.split_at(attributes.len() / 2);
+        let mut out = bisect(left);
+        out.extend(bisect(right));
+        out
+    }
+
+    bisect(attributes)
+}
+
 pub fn uptodate() -> Result<Option<(String, String)>> {
-    let legacyver = fs::read_to_string(&format!("{}/legacypkgs.ver", &*CACHEDIR))?;
-    let nixosver = fs::read_to_string(&format!("{}/nixospkgs.ver", &*CACHEDIR))?;
+    let legacyver = fs::read_to_string(format!("{}/legacypkgs.ver", cachedir()))?;
+    let nixosver = fs::read_to_string(format!("{}/nixospkgs.ver", cachedir()))?;
     if !nixosver.eq(&legacyver) {
         Ok(Some((legacyver, nixosver)))
     } else {
@@ -153,44 +323,26 @@ pub fn uptodate() -> Result<Option<(String, String)>> {
 }
 
 pub async fn unavailablepkgs(paths: &[&str]) -> Result<HashMap<String, String>> {
-    let aliases = Command::new("nix-instantiate")
+    let aliases = utils::output(Command::new("nix-instantiate")
         .arg("--eval")
         .arg("-E")
         .arg("with import <nixpkgs> {}; builtins.attrNames ((self: super: lib.optionalAttrs config.allowAliases (import <nixpkgs/pkgs/top-level/aliases.nix> lib self super)) {} {})")
-        .arg("--json")
-        .output()?;
+        .arg("--json"))?;
     let aliasstr = String::from_utf8(aliases.stdout)?;
     let aliasesout: HashSet<String> = serde_json::from_str(&aliasstr)?;
 
-    let pkgs = {
-        let mut allpkgs: HashSet<String> = HashSet::new();
-        for path in paths {
-            if let Ok(filepkgs) = nix_editor::read::getarrvals(
-                &fs::read_to_string(path)?,
-                "environment.systemPackages",
-            ) {
-                let filepkgset = filepkgs
-                    .into_iter()
-                    .map(|x| x.strip_prefix("pkgs.").unwrap_or(&x).to_string())
-                    .collect::<HashSet<_>>();
-                allpkgs = allpkgs.union(&filepkgset).map(|x| x.to_string()).collect();
-            }
-        }
-        allpkgs
-    };
+    let pkgs = nixos::readpkgs(paths, "environment.systemPackages")?;
 
     let mut unavailable = HashMap::new();
     for pkg in pkgs {
-        if aliasesout.contains(&pkg) && Command::new("nix-instantiate")
+        if aliasesout.contains(&pkg) && utils::output(Command::new("nix-instantiate")
                 .arg("--eval")
                 .arg("-E")
-                .arg(&format!("with import <nixpkgs> {{}}; builtins.tryEval ((self: super: lib.optionalAttrs config.allowAliases (import <nixpkgs/pkgs/top-level/aliases.nix> lib self super)) {{}} {{}}).{}", pkg))
-                .output()?.status.success() {
-            let out = Command::new("nix-instantiate")
+                .arg(format!("with import <nixpkgs> {{}}; builtins.tryEval ((self: super: lib.optionalAttrs config.allowAliases (import <nixpkgs/pkgs/top-level/aliases.nix> lib self super)) {{}} {{}}).{}", pkg)))?.status.success() {
+            let out = utils::output(Command::new("nix-instantiate")
                 .arg("--eval")
                 .arg("-E")
-                .arg(&format!("with import <nixpkgs> {{}}; ((self: super: lib.optionalAttrs config.allowAliases (import <nixpkgs/pkgs/top-level/aliases.nix> lib self super)) {{}} {{}}).{}", pkg))
-                .output()?;
+                .arg(format!("with import <nixpkgs> {{}}; ((self: super: lib.optionalAttrs config.allowAliases (import <nixpkgs/pkgs/top-level/aliases.nix> lib self super)) {{}} {{}}).{}", pkg)))?;
             let err = String::from_utf8(out.stderr)?;
             let err = err.strip_prefix("error: ").unwrap_or(&err).trim();
             unavailable.insert(pkg, err.to_string());
@@ -198,25 +350,82 @@ pub async fn unavailablepkgs(paths: &[&str]) -> Result<HashMap<String, String>>
     }
 
     let legacypkgs = getlegacypkgs(paths).await?;
-    let nixospkgs = nixospkgs().await?;
-    let pool = SqlitePool::connect(&format!("sqlite://{}", nixospkgs)).await?;
-
-    for (pkg, _) in legacypkgs {
-        let (x, broken, insecure): (String, u8, u8) =
-            sqlx::query_as("SELECT attribute,broken,insecure FROM meta WHERE attribute = $1")
-                .bind(&pkg)
-                .fetch_one(&pool)
-                .await?;
-        if x != pkg {
-            unavailable.insert(
-                pkg,
-                String::from("Package not found in newer version of nixpkgs"),
-            );
-        } else if broken == 1 {
-            unavailable.insert(pkg, String::from("Package is marked as broken"));
-        } else if insecure == 1 {
-            unavailable.insert(pkg, String::from("Package is marked as insecure"));
+    nixos::unavailablemeta(&legacypkgs.keys().collect::<Vec<_>>(), &mut unavailable).await?;
+    Ok(unavailable)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PYTHON: &str = "/nix/store/0c8b5csxrk5ngn8jqzf9cmd3x2iky4h9-python3-3.11.4";
+
+    fn candidates(attributes: &[&str]) -> Vec<String> {
+        attributes.iter().map(|a| a.to_string()).collect()
+    }
+
+    fn resolved(attribute: &str, confidence: Confidence) -> Resolution {
+        Resolution::Resolved {
+            attribute: attribute.to_string(),
+            confidence,
         }
     }
-    Ok(unavailable)
+
+    #[test]
+    fn resolvesbystorepath() {
+        let storepath = StorePath::parse(PYTHON).unwrap();
+        let outpaths = HashMap::from([
+            (
+                String::from("python310"),
+                HashSet::from([String::from("/nix/store/other")]),
+            ),
+            (String::from("python3"), HashSet::from([PYTHON.to_string()])),
+            (
+                String::from("python311"),
+                HashSet::from([PYTHON.to_string()]),
+            ),
+        ]);
+        // The most likely attribute with the installed store path wins
+        assert_eq!(
+            resolve(
+                candidates(&["python310", "python311", "python3"]),
+                false,
+                Some(&storepath),
+                &outpaths
+            ),
+            resolved("python311", Confidence::StorePath)
+        );
+        // Without a matching store path, several candidates are ambiguous
+        assert_eq!(
+            resolve(
+                candidates(&["python310", "python39"]),
+                true,
+                Some(&storepath),
+                &outpaths
+            ),
+            Resolution::Ambiguous(candidates(&["python310", "python39"]))
+        );
+    }
+
+    #[test]
+    fn resolvessinglecandidates() {
+        let outpaths = HashMap::new();
+        assert_eq!(
+            resolve(candidates(&["hello"]), true, None, &outpaths),
+            resolved("hello", Confidence::Version)
+        );
+        assert_eq!(
+            resolve(candidates(&["hello"]), false, None, &outpaths),
+            resolved("hello", Confidence::Name)
+        );
+        assert_eq!(
+            resolve(Vec::new(), false, None, &outpaths),
+            Resolution::NotFound
+        );
+        assert_eq!(
+            resolved("hello", Confidence::Name).attribute(),
+            Some("hello")
+        );
+        assert_eq!(Resolution::NotFound.attribute(), None);
+    }
 }
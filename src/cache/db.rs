@@ -0,0 +1,441 @@
+use crate::Result;
+use serde_json::Value;
+use sqlx::{sqlite::SqliteConnectOptions, SqliteConnection, SqlitePool};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    os::unix::fs::MetadataExt,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use super::{nixos, nonnixos, CachedFile};
+
+/// Handle to a cached package database, such as the one returned by [nixospkgs()](super::nixos::nixospkgs).
+///
+/// Handles are cheap to clone and shared: opening the same file again reuses its connections
+/// until the file is replaced by a refresh.
+/// Lookups of several packages are done in a single query.
+///
+/// # Example
+/// ```no_run
+/// # async fn example() -> nix_data::Result<()> {
+/// use nix_data::cache::{db::PackageDb, nixos::nixospkgs};
+///
+/// let db = PackageDb::open(nixospkgs().await?).await?;
+/// let versions = db.versions(&["firefox", "hello"]).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct PackageDb {
+    path: String,
+    pool: SqlitePool,
+    /// Columns the database has.
+    columns: Arc<Columns>,
+    /// Query used by [PackageDb::get_packages()], depending on which columns the database has.
+    infoquery: Arc<str>,
+}
+
+/// Status of a package in the `meta` table of a full package database
+/// such as [nixospkgs()](super::nixos::nixospkgs).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PkgMeta {
+    pub broken: bool,
+    pub insecure: bool,
+}
+
+/// Information about a package.
+/// Fields that the database doesn't contain are empty,
+/// as databases with only versions such as [nixpkgslatest()](super::profile::nixpkgslatest) have no `meta` table.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PackageInfo {
+    pub attribute: String,
+    pub pname: Option<String>,
+    pub version: Option<String>,
+    pub description: Option<String>,
+    pub longdescription: Option<String>,
+    pub homepage: Option<String>,
+    pub licenses: Vec<License>,
+    pub maintainers: Vec<Maintainer>,
+    pub platforms: Vec<String>,
+    /// Name of the main executable, from `meta.mainProgram`.
+    pub mainprogram: Option<String>,
+    pub broken: bool,
+    pub insecure: bool,
+    /// Whether the package is unfree, from `meta.unfree` or otherwise from its licenses.
+    pub unfree: bool,
+}
+
+/// License of a package, from `meta.license`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct License {
+    pub spdxid: Option<String>,
+    pub fullname: Option<String>,
+    pub shortname: Option<String>,
+    pub url: Option<String>,
+    pub free: Option<bool>,
+}
+
+/// Maintainer of a package, from `meta.maintainers`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Maintainer {
+    pub name: Option<String>,
+    pub email: Option<String>,
+    pub github: Option<String>,
+}
+
+#[derive(sqlx::FromRow)]
+struct InfoRow {
+    attribute: String,
+    pname: Option<String>,
+    version: Option<String>,
+    description: Option<String>,
+    longdescription: Option<String>,
+    homepage: Option<String>,
+    license: Option<String>,
+    maintainers: Option<String>,
+    platforms: Option<String>,
+    mainprogram: Option<String>,
+    broken: Option<bool>,
+    insecure: Option<bool>,
+    unfree: Option<bool>,
+}
+
+/// Identifies a version of a database file, which changes when a refresh replaces it.
+#[derive(PartialEq, Eq)]
+struct FileId {
+    ino: u64,
+    modified: SystemTime,
+}
+
+lazy_static::lazy_static! {
+    static ref OPEN: Mutex<HashMap<String, (FileId, PackageDb)>> = Mutex::new(HashMap::new());
+}
+
+impl PackageDb {
+    /// Opens the database at `path`, reusing an already open handle to the same file.
+    pub async fn open<P: AsRef<Path>>(path: P) -> Result<PackageDb> {
+        let path = path.as_ref().to_string_lossy().to_string();
+        let metadata = fs::metadata(&path)?;
+        let id = FileId {
+            ino: metadata.ino(),
+            modified: metadata.modified()?,
+        };
+        {
+            let open = OPEN.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some((openid, db)) = open.get(&path) {
+                if *openid == id {
+                    return Ok(db.clone());
+                }
+            }
+        }
+        let pool = SqlitePool::connect_with(SqliteConnectOptions::new().filename(&path)).await?;
+        let columns = Columns::read(&mut *pool.acquire().await?, "main").await?;
+        let db = PackageDb {
+            path: path.to_string(),
+            pool,
+            infoquery: infoquery(&columns).into(),
+            columns: Arc::new(columns),
+        };
+        OPEN.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(path, (id, db.clone()));
+        Ok(db)
+    }
+
+    /// Path to the database file.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Returns the version of the package with the attribute `attribute`, if it is in the database.
+    pub async fn version(&self, attribute: &str) -> Result<Option<String>> {
+        Ok(self.versions(&[attribute]).await?.remove(attribute))
+    }
+
+    /// Returns the versions of all `attributes` found in the database.
+    pub async fn versions<S: AsRef<str>>(
+        &self,
+        attributes: &[S],
+    ) -> Result<HashMap<String, String>> {
+        let rows: Vec<(String, String)> = sqlx::query_as(
+            r#"
+            SELECT attribute, version FROM pkgs WHERE attribute IN (SELECT value FROM json_each($1))
+            "#,
+        )
+        .bind(jsonarray(attributes)?)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().collect())
+    }
+
+    /// Returns the attributes and versions of all packages with each of `pnames` found in the database.
+    /// Attributes named like the package come first, followed by the others from shortest to longest.
+    /// Databases without a `pname` column, such as [nixpkgslatest()](super::profile::nixpkgslatest), have no matches.
+    pub async fn allbypname<S: AsRef<str>>(
+        &self,
+        pnames: &[S],
+    ) -> Result<HashMap<String, Vec<(String, String)>>> {
+        if !self.columns.has("pkgs", "pname") {
+            return Ok(HashMap::new());
+        }
+        let rows: Vec<(String, String, String)> = sqlx::query_as(
+            r#"
+            SELECT pname, attribute, version FROM pkgs WHERE pname IN (SELECT value FROM json_each($1))
+            ORDER BY attribute = pname DESC, length(attribute), attribute
+            "#,
+        )
+        .bind(jsonarray(pnames)?)
+        .fetch_all(&self.pool)
+        .await?;
+        let mut out: HashMap<String, Vec<(String, String)>> = HashMap::new();
+        for (pname, attribute, version) in rows {
+            out.entry(pname).or_default().push((attribute, version));
+        }
+        Ok(out)
+    }
+
+    /// Returns the status of all `attributes` found in the `meta` table.
+    pub async fn meta<S: AsRef<str>>(&self, attributes: &[S]) -> Result<HashMap<String, PkgMeta>> {
+        let rows: Vec<(String, Option<bool>, Option<bool>)> = sqlx::query_as(
+            r#"
+            SELECT attribute, broken, insecure FROM meta WHERE attribute IN (SELECT value FROM json_each($1))
+            "#,
+        )
+        .bind(jsonarray(attributes)?)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(attribute, broken, insecure)| {
+                (
+                    attribute,
+                    PkgMeta {
+                        broken: broken.unwrap_or(false),
+                        insecure: insecure.unwrap_or(false),
+                    },
+                )
+            })
+            .collect())
+    }
+
+    /// Returns information about the package with the attribute `attribute`, if it is in the database.
+    pub async fn get_package(&self, attribute: &str) -> Result<Option<PackageInfo>> {
+        Ok(self.get_packages(&[attribute]).await?.remove(attribute))
+    }
+
+    /// Returns information about all `attributes` found in the database.
+    pub async fn get_packages<S: AsRef<str>>(
+        &self,
+        attributes: &[S],
+    ) -> Result<HashMap<String, PackageInfo>> {
+        let rows: Vec<InfoRow> = sqlx::query_as(&self.infoquery)
+            .bind(jsonarray(attributes)?)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.attribute.to_string(), PackageInfo::from(row)))
+            .collect())
+    }
+}
+
+/// Package database for the current system,
+/// [nixospkgs()](super::nixos::nixospkgs) on NixOS and [nixpkgs()](super::nonnixos::nixpkgs) otherwise.
+pub(crate) async fn systempkgs() -> Result<CachedFile> {
+    if Path::new("/etc/NIXOS").exists() {
+        nixos::nixospkgs().await
+    } else {
+        nonnixos::nixpkgs().await
+    }
+}
+
+/// Returns information about the package with the attribute `attribute` from the package database for the current system.
+pub async fn get_package(attribute: &str) -> Result<Option<PackageInfo>> {
+    PackageDb::open(systempkgs().await?)
+        .await?
+        .get_package(attribute)
+        .await
+}
+
+/// Returns information about all `attributes` found in the package database for the current system.
+pub async fn get_packages<S: AsRef<str>>(attributes: &[S]) -> Result<HashMap<String, PackageInfo>> {
+    PackageDb::open(systempkgs().await?)
+        .await?
+        .get_packages(attributes)
+        .await
+}
+
+/// Columns of the `pkgs` and `meta` tables of a package database, which differ between databases.
+/// Databases with only versions have no `meta` table, and older ones lack some of its columns.
+#[derive(Debug)]
+pub(crate) struct Columns(HashSet<String>);
+
+impl Columns {
+    /// Reads the columns of the database attached as `schema`, such as `main`.
+    pub async fn read(conn: &mut SqliteConnection, schema: &str) -> Result<Columns> {
+        let mut columns = HashSet::new();
+        for table in ["pkgs", "meta"] {
+            let names: Vec<(String,)> =
+                sqlx::query_as("SELECT name FROM pragma_table_info($1, $2)")
+                    .bind(table)
+                    .bind(schema)
+                    .fetch_all(&mut *conn)
+                    .await?;
+            for (name,) in names {
+                columns.insert(format!("{}.{}", table, name.to_lowercase()));
+            }
+        }
+        Ok(Columns(columns))
+    }
+
+    pub fn hasmeta(&self) -> bool {
+        self.0.iter().any(|c| c.starts_with("meta."))
+    }
+
+    fn has(&self, table: &str, column: &str) -> bool {
+        self.0.contains(&format!("{}.{}", table, column))
+    }
+
+    /// Selects `column` of `table` through the alias `alias`, or `NULL` if the database doesn't have it.
+    pub fn select(&self, table: &str, column: &str, alias: &str) -> String {
+        if self.has(table, column) {
+            format!("{}.{} AS {}", alias, column, column)
+        } else {
+            format!("NULL AS {}", column)
+        }
+    }
+
+    /// Selects whether a package is unfree from the `meta` table through the alias `alias`.
+    /// Packages without an `unfree` value are unfree if any of their licenses isn't free,
+    /// which is how nixpkgs marks them.
+    pub fn unfree(&self, alias: &str) -> String {
+        let fromlicense = format!(
+            r#"CASE WHEN json_valid({a}.license) THEN EXISTS (
+                SELECT 1 FROM json_each(CASE json_type({a}.license) WHEN 'array' THEN {a}.license ELSE json_array(json({a}.license)) END)
+                WHERE CASE WHEN type = 'object' THEN json_extract(value, '$.free') END = 0
+            ) END"#,
+            a = alias
+        );
+        match (self.has("meta", "unfree"), self.has("meta", "license")) {
+            (true, true) => format!("coalesce({}.unfree, {}) AS unfree", alias, fromlicense),
+            (true, false) => format!("{}.unfree AS unfree", alias),
+            (false, true) => format!("{} AS unfree", fromlicense),
+            (false, false) => String::from("NULL AS unfree"),
+        }
+    }
+}
+
+/// Builds the query for [PackageDb::get_packages()], selecting `NULL` for missing columns.
+fn infoquery(columns: &Columns) -> String {
+    let select = [
+        ("pkgs", "pname"),
+        ("pkgs", "version"),
+        ("meta", "description"),
+        ("meta", "longdescription"),
+        ("meta", "homepage"),
+        ("meta", "license"),
+        ("meta", "maintainers"),
+        ("meta", "platforms"),
+        ("meta", "mainprogram"),
+        ("meta", "broken"),
+        ("meta", "insecure"),
+    ]
+    .iter()
+    .map(|(table, column)| columns.select(table, column, table))
+    .chain([columns.unfree("meta")])
+    .collect::<Vec<_>>()
+    .join(", ");
+    format!(
+        "SELECT pkgs.attribute AS attribute, {} FROM pkgs {} WHERE pkgs.attribute IN (SELECT value FROM json_each($1))",
+        select,
+        if columns.hasmeta() {
+            "LEFT JOIN meta ON meta.attribute = pkgs.attribute"
+        } else {
+            ""
+        }
+    )
+}
+
+/// Parses a JSON column that holds either a single value or a list of them.
+fn jsonlist(column: Option<String>) -> Vec<Value> {
+    let value = match column {
+        Some(c) => serde_json::from_str(&c).unwrap_or(Value::String(c)),
+        None => return vec![],
+    };
+    match value {
+        Value::Array(values) => values,
+        Value::Null => vec![],
+        value => vec![value],
+    }
+}
+
+fn jsonstr(value: &Value, key: &str) -> Option<String> {
+    value
+        .get(key)
+        .and_then(|v| v.as_str())
+        .map(|v| v.to_string())
+}
+
+impl From<InfoRow> for PackageInfo {
+    fn from(row: InfoRow) -> Self {
+        PackageInfo {
+            attribute: row.attribute,
+            pname: row.pname,
+            version: row.version,
+            description: row.description,
+            longdescription: row.longdescription,
+            homepage: jsonlist(row.homepage)
+                .first()
+                .and_then(|h| h.as_str())
+                .map(|h| h.to_string()),
+            licenses: jsonlist(row.license)
+                .iter()
+                .map(|l| match l {
+                    Value::String(name) => License {
+                        fullname: Some(name.to_string()),
+                        ..Default::default()
+                    },
+                    l => License {
+                        spdxid: jsonstr(l, "spdxId"),
+                        fullname: jsonstr(l, "fullName"),
+                        shortname: jsonstr(l, "shortName"),
+                        url: jsonstr(l, "url"),
+                        free: l.get("free").and_then(|f| f.as_bool()),
+                    },
+                })
+                .collect(),
+            maintainers: jsonlist(row.maintainers)
+                .iter()
+                .map(|m| match m {
+                    Value::String(name) => Maintainer {
+                        name: Some(name.to_string()),
+                        ..Default::default()
+                    },
+                    m => Maintainer {
+                        name: jsonstr(m, "name"),
+                        email: jsonstr(m, "email"),
+                        github: jsonstr(m, "github"),
+                    },
+                })
+                .collect(),
+            platforms: jsonlist(row.platforms)
+                .iter()
+                .filter_map(|p| p.as_str().map(|p| p.to_string()))
+                .collect(),
+            mainprogram: row.mainprogram,
+            broken: row.broken.unwrap_or(false),
+            insecure: row.insecure.unwrap_or(false),
+            unfree: row.unfree.unwrap_or(false),
+        }
+    }
+}
+
+/// Encodes `attributes` as a JSON array, so any number of them can be bound to a single parameter.
+fn jsonarray<S: AsRef<str>>(attributes: &[S]) -> Result<String> {
+    Ok(serde_json::to_string(
+        &attributes.iter().map(|a| a.as_ref()).collect::<Vec<_>>(),
+    )?)
+}
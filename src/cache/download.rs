@@ -0,0 +1,162 @@
+use brotli::DecompressorWriter;
+use std::{
+    fmt,
+    io::{self, Write},
+};
+
+use super::{
+    mirror::MirrorResponse,
+    progress::{self, Phase},
+    verify::{IntegrityError, Verifier},
+};
+
+/// Error returned when a download can't be decompressed or stored.
+/// Partially written files are removed and the previously cached data is kept.
+#[derive(Debug)]
+pub enum DownloadError {
+    /// Reading the response body failed, usually because the connection dropped.
+    Interrupted(String),
+    /// The download isn't valid or complete brotli data.
+    Decompress(io::Error),
+    /// Writing the decompressed data failed.
+    Write(io::Error),
+    /// The download failed verification.
+    Integrity(IntegrityError),
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DownloadError::Interrupted(e) => write!(f, "Download interrupted: {}", e),
+            DownloadError::Decompress(e) => write!(f, "Failed to decompress download: {}", e),
+            DownloadError::Write(e) => write!(f, "Failed to write download: {}", e),
+            DownloadError::Integrity(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for DownloadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DownloadError::Interrupted(_) => None,
+            DownloadError::Decompress(e) | DownloadError::Write(e) => Some(e),
+            DownloadError::Integrity(e) => Some(e),
+        }
+    }
+}
+
+impl From<IntegrityError> for DownloadError {
+    fn from(e: IntegrityError) -> Self {
+        DownloadError::Integrity(e)
+    }
+}
+
+/// Writer that keeps the errors of `inner`, so they can be told apart from decompression errors.
+struct Tracked<W> {
+    inner: W,
+    written: u64,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> Write for Tracked<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.inner.write(buf) {
+            Ok(size) => {
+                self.written += size as u64;
+                Ok(size)
+            }
+            Err(e) => {
+                let kind = e.kind();
+                self.error = Some(e);
+                Err(kind.into())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Brotli decompression of a download as its chunks arrive.
+struct Decompress<'a, W: Write> {
+    entry: &'a str,
+    writer: DecompressorWriter<Tracked<W>>,
+    verifier: Option<Verifier<'a>>,
+    done: u64,
+    total: Option<u64>,
+}
+
+impl<'a, W: Write> Decompress<'a, W> {
+    fn new(entry: &'a str, out: W, verifier: Option<Verifier<'a>>, total: Option<u64>) -> Self {
+        progress::report(entry, Phase::Downloading, 0, total);
+        Decompress {
+            entry,
+            writer: DecompressorWriter::new(
+                Tracked {
+                    inner: out,
+                    written: 0,
+                    error: None,
+                },
+                4096,
+            ),
+            verifier,
+            done: 0,
+            total,
+        }
+    }
+
+    fn error(&mut self, e: io::Error) -> DownloadError {
+        match self.writer.get_mut().error.take() {
+            Some(e) => DownloadError::Write(e),
+            None => DownloadError::Decompress(e),
+        }
+    }
+
+    fn feed(&mut self, chunk: &[u8]) -> Result<(), DownloadError> {
+        if let Some(verifier) = &mut self.verifier {
+            verifier.update(chunk);
+        }
+        if let Err(e) = self.writer.write_all(chunk) {
+            return Err(self.error(e));
+        }
+        self.done += chunk.len() as u64;
+        progress::report(self.entry, Phase::Downloading, self.done, self.total);
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<W, DownloadError> {
+        // Reject the download before anything is committed
+        if let Some(verifier) = self.verifier.take() {
+            verifier.finish()?;
+        }
+        if let Err(e) = self.writer.close() {
+            return Err(self.error(e));
+        }
+        let written = self.writer.get_ref().written;
+        progress::report(self.entry, Phase::Decompressing, written, Some(written));
+        match self.writer.into_inner() {
+            Ok(tracked) => Ok(tracked.inner),
+            Err(_) => Err(DownloadError::Decompress(io::ErrorKind::InvalidData.into())),
+        }
+    }
+}
+
+/// Streams the brotli compressed body of `resp` into `out` without buffering it,
+/// verifying it along the way when `verifier` is set.
+pub(crate) async fn decompress<'a, W: Write>(
+    entry: &'a str,
+    resp: &mut MirrorResponse,
+    verifier: Option<Verifier<'a>>,
+    out: W,
+) -> Result<W, DownloadError> {
+    let mut stream = Decompress::new(entry, out, verifier, resp.contentlength());
+    while let Some(chunk) = resp
+        .chunk()
+        .await
+        .map_err(|e| DownloadError::Interrupted(e.to_string()))?
+    {
+        stream.feed(&chunk)?;
+    }
+    stream.finish()
+}
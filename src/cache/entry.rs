@@ -0,0 +1,661 @@
+use crate::{
+    config::configfile::{getconfigordefault, CachePolicy},
+    context::cachedir,
+    utils, Error, Result,
+};
+use filetime::FileTime;
+use log::{debug, info};
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+use tokio::process::Command;
+
+use super::{
+    atomic::{self, AtomicFile},
+    download,
+    lock::CacheLock,
+    mirror, nixos, profile,
+    progress::{self, Phase},
+    verify::Integrity,
+    CachedFile, NixPkg, NixPkgList,
+};
+
+/// Cache policy from the config file. Errors if the config file is invalid.
+pub(crate) fn cachepolicy() -> Result<CachePolicy> {
+    Ok(getconfigordefault()?.cachepolicy.unwrap_or_default())
+}
+
+/// Format of a downloaded file.
+pub(crate) enum Format {
+    /// Brotli compressed file that is stored as is, such as an SQLite database.
+    Raw,
+    /// Brotli compressed JSON object mapping attributes to versions, imported into a `pkgs` table.
+    Versions,
+    /// Brotli compressed `packages.json` from a NixOS release, imported into a `pkgs` table.
+    Packages,
+}
+
+/// Where the contents of a cache entry come from.
+pub(crate) enum Source {
+    /// A file on a mirror.
+    Download {
+        url: String,
+        format: Format,
+        /// Whether the mirror publishes checksums and signatures for the file,
+        /// so `requirechecksum` and `publickey` from the config apply to it.
+        /// The upstream NixOS channels and releases don't.
+        verify: bool,
+    },
+    /// Output of `nix search --json` for a flake reference, imported into a `pkgs` table.
+    NixSearch(String),
+    /// A file in the output of `nix build` for an installable, stored as is.
+    NixBuild { installable: String, path: String },
+}
+
+/// How the latest version of a cache entry is determined.
+pub(crate) enum Version {
+    /// Version is known locally, such as the running NixOS version.
+    Known(String),
+    /// Contents of a file on a mirror, such as a `.ver` file or the `git-revision` of a NixOS channel.
+    Url(String),
+    /// Latest revision of a flake reference, from `nix flake metadata`.
+    Flake(String),
+}
+
+/// A version of a cache entry along with the sources it can be fetched from, in order of preference.
+pub(crate) struct Release {
+    pub version: Version,
+    pub sources: Vec<Source>,
+}
+
+impl Release {
+    /// URL of the checksum file for the download `url`.
+    /// Releases with a `.ver` file publish a `.sha256` manifest next to it, listing the checksums of all its files.
+    /// Other releases, such as version data for a specific nixpkgs revision, have no `.ver` file,
+    /// so the checksum is read from `{url}.sha256` next to the download instead.
+    fn checksumurl(&self, url: &str) -> String {
+        match &self.version {
+            Version::Url(ver) if ver.ends_with(".ver") => {
+                format!("{}.sha256", ver.trim_end_matches(".ver"))
+            }
+            _ => format!("{}.sha256", url),
+        }
+    }
+}
+
+/// A versioned file in the cache directory.
+/// The data is stored in `{name}.{ext}` and the version it was fetched for in `{name}.ver`.
+pub(crate) struct CacheEntry {
+    pub name: &'static str,
+    pub ext: &'static str,
+    /// Candidate releases. The first one whose version can be found is used.
+    pub releases: Vec<Release>,
+}
+
+impl CacheEntry {
+    pub fn path(&self) -> String {
+        format!("{}/{}.{}", cachedir(), self.name, self.ext)
+    }
+
+    pub fn verpath(&self) -> String {
+        format!("{}/{}.ver", cachedir(), self.name)
+    }
+
+    /// Version of the currently cached data, if any.
+    pub fn cachedversion(&self) -> Option<String> {
+        if Path::new(&self.path()).exists() {
+            fs::read_to_string(self.verpath()).ok()
+        } else {
+            None
+        }
+    }
+
+    /// Whether the cached data matches `version`.
+    pub fn isfresh(&self, version: &str) -> bool {
+        self.cachedversion().as_deref() == Some(version)
+    }
+
+    /// The currently cached data.
+    pub fn cached(&self, stale: bool) -> CachedFile {
+        CachedFile {
+            path: self.path(),
+            version: self.cachedversion(),
+            stale,
+        }
+    }
+
+    /// Whether the cached data was last checked for a newer version within `age`.
+    fn checkedwithin(&self, age: Duration) -> bool {
+        self.cachedversion().is_some()
+            && fs::metadata(self.verpath())
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|t| t.elapsed().ok())
+                .map(|elapsed| elapsed < age)
+                .unwrap_or(false)
+    }
+
+    /// Whether the cached data was checked or written since `time`, such as by another process.
+    pub fn checkedsince(&self, time: SystemTime) -> bool {
+        self.cachedversion().is_some()
+            && fs::metadata(self.verpath())
+                .and_then(|m| m.modified())
+                .map(|t| t >= time)
+                .unwrap_or(false)
+    }
+
+    /// Records that the cached data was just checked and is up to date.
+    pub fn touch(&self) -> Result<()> {
+        filetime::set_file_mtime(self.verpath(), FileTime::now())?;
+        Ok(())
+    }
+
+    /// Returns the cached data if `policy` says the mirror shouldn't be checked.
+    pub fn skipcheck(&self, policy: CachePolicy) -> Option<Result<CachedFile>> {
+        match policy {
+            CachePolicy::AlwaysRefresh => None,
+            CachePolicy::RefreshIfOlderThan(age) if self.checkedwithin(age) => {
+                debug!("{} was checked recently, not refreshing", self.name);
+                Some(Ok(self.cached(false)))
+            }
+            CachePolicy::RefreshIfOlderThan(_) => None,
+            CachePolicy::OfflineOnly => Some(self.fallback()),
+        }
+    }
+
+    /// Returns the previously cached data when the latest version can't be fetched.
+    pub fn fallback(&self) -> Result<CachedFile> {
+        if Path::new(&self.path()).exists() {
+            info!("Using old {}", self.name);
+            Ok(self.cached(true))
+        } else if cachepolicy()? == CachePolicy::OfflineOnly {
+            Err(Error::Cache(format!(
+                "No cached version of {} exists and the cache policy is offline-only",
+                self.name
+            )))
+        } else {
+            Err(Error::Cache(format!(
+                "Could not find latest version of {} and no cached version exists",
+                self.name
+            )))
+        }
+    }
+
+    /// Makes sure the latest version of the entry is cached and returns it.
+    /// Falls back to the previously cached data if the mirror can't be reached,
+    /// and follows the [CachePolicy] set in the config.
+    ///
+    /// Only one process refreshes an entry at a time.
+    /// Others wait for it and reuse its result.
+    pub async fn get(&self) -> Result<CachedFile> {
+        let out = self.lockedrefresh().await;
+        progress::report(self.name, Phase::Done, 0, None);
+        out
+    }
+
+    async fn lockedrefresh(&self) -> Result<CachedFile> {
+        let start = SystemTime::now();
+        let _lock = CacheLock::acquire(self.name).await?;
+        if self.checkedsince(start) {
+            debug!("{} was refreshed by another process", self.name);
+            return Ok(self.cached(false));
+        }
+        self.refresh(cachepolicy()?).await
+    }
+
+    async fn refresh(&self, policy: CachePolicy) -> Result<CachedFile> {
+        progress::report(self.name, Phase::CheckingVersion, 0, None);
+        let mut found = None;
+        for release in &self.releases {
+            match &release.version {
+                Version::Known(v) => {
+                    found = Some((release, v.to_string()));
+                    break;
+                }
+                Version::Url(url) => {
+                    if let Some(out) = self.skipcheck(policy) {
+                        return out;
+                    }
+                    debug!("Checking {} version", self.name);
+                    let resp = if let Some(r) = mirror::tryget(url).await? {
+                        r
+                    } else {
+                        // Internet connection failed
+                        return self.fallback();
+                    };
+                    if resp.success {
+                        let v = resp.text().await?;
+                        let v = v.trim();
+                        let v = v.strip_prefix("nixos-").unwrap_or(v).to_string();
+                        found = Some((release, v));
+                        break;
+                    }
+                }
+                Version::Flake(flakeref) => {
+                    if let Some(out) = self.skipcheck(policy) {
+                        return out;
+                    }
+                    debug!("Checking {} version", self.name);
+                    let flake = flakeref.to_string();
+                    // `nix` may take a while to fetch the flake, so keep it off the async runtime
+                    let metadata =
+                        tokio::task::spawn_blocking(move || profile::flakemetadata(&flake, true))
+                            .await
+                            .map_err(|e| Error::Cache(e.to_string()))??;
+                    match metadata {
+                        Some(metadata) => {
+                            let rev = metadata.rev().ok_or_else(|| {
+                                Error::Parse(format!("No revision found for {}", flakeref))
+                            })?;
+                            found = Some((release, rev.to_string()));
+                            break;
+                        }
+                        // Fetching the flake failed
+                        None => return self.fallback(),
+                    }
+                }
+            }
+        }
+        let (release, version) = found.ok_or_else(|| {
+            Error::Cache(format!("Could not find latest version of {}", self.name))
+        })?;
+        debug!("Latest {} version: {}", self.name, version);
+
+        // Check if latest version is already downloaded
+        if self.isfresh(&version) {
+            debug!("No new version of {} found", self.name);
+            self.touch()?;
+            return Ok(self.cached(false));
+        }
+        // Known to be outdated, but fetching needs the network
+        if policy == CachePolicy::OfflineOnly {
+            return self.fallback();
+        }
+
+        let mut offline = false;
+        for source in &release.sources {
+            let data = AtomicFile::new(self.path())?;
+            match self.fetch(release, source, &data).await? {
+                Fetched::Done => {
+                    debug!("Writing {} version", self.name);
+                    atomic::commitversioned(data, &self.verpath(), &version)?;
+                    return Ok(self.cached(false));
+                }
+                Fetched::Missing => {}
+                Fetched::Unreachable => offline = true,
+            }
+        }
+        if offline {
+            self.fallback()
+        } else {
+            Err(Error::Cache(format!(
+                "Failed to download latest {}",
+                self.name
+            )))
+        }
+    }
+
+    /// Fetches `source` of `release` into the temporary path of `data`.
+    async fn fetch(
+        &self,
+        release: &Release,
+        source: &Source,
+        data: &AtomicFile,
+    ) -> Result<Fetched> {
+        let (url, format, verify) = match source {
+            Source::Download {
+                url,
+                format,
+                verify,
+            } => (url, format, *verify),
+            Source::NixSearch(flake) => {
+                let pkgsout =
+                    utils::outputasync(Command::new("nix").arg("search").arg("--json").arg(flake))
+                        .await?;
+                let pkgsjson: HashMap<String, NixPkg> = serde_json::from_slice(&pkgsout.stdout)?;
+                let pkgsjson = pkgsjson
+                    .iter()
+                    .map(|(k, v)| {
+                        (
+                            k.split('.').skip(2).collect::<Vec<_>>().join("."),
+                            v.version.to_string(),
+                        )
+                    })
+                    .collect::<HashMap<String, String>>();
+                nixos::createdb(self.name, data.tmppath(), &pkgsjson).await?;
+                return Ok(Fetched::Done);
+            }
+            Source::NixBuild { installable, path } => {
+                debug!("Building {}", installable);
+                // Uses the flake fetched by `nix flake metadata --refresh` when checking the version
+                let output = utils::outputasync(
+                    Command::new("nix")
+                        .arg("build")
+                        .arg("--no-link")
+                        .arg("--print-out-paths")
+                        .arg(installable),
+                )
+                .await?;
+                if !output.status.success() {
+                    info!(
+                        "Failed to build {}: {}",
+                        installable,
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    );
+                    return Ok(Fetched::Unreachable);
+                }
+                let outpath = String::from_utf8(output.stdout)?;
+                let outpath = outpath.lines().next().unwrap_or_default();
+                tokio::io::copy(
+                    &mut tokio::fs::File::open(Path::new(outpath).join(path)).await?,
+                    &mut tokio::fs::File::from_std(data.create()?),
+                )
+                .await?;
+                return Ok(Fetched::Done);
+            }
+        };
+
+        debug!("Downloading {}", url);
+        let mut resp = match mirror::tryget(url).await? {
+            Some(r) => r,
+            None => return Ok(Fetched::Unreachable),
+        };
+        if !resp.success {
+            return Ok(Fetched::Missing);
+        }
+
+        // Only sources that exist need a checksum or signature, so missing ones fall through to the next source
+        let integrity = if verify {
+            match Integrity::fetch(url, &release.checksumurl(url)).await? {
+                Some(i) => Some(i),
+                None => return Ok(Fetched::Unreachable),
+            }
+        } else {
+            None
+        };
+        let verifier = integrity.as_ref().map(|i| i.verifier()).transpose()?;
+        match format {
+            Format::Raw => {
+                debug!("Writing {}", self.name);
+                download::decompress(self.name, &mut resp, verifier, data.create()?).await?;
+            }
+            Format::Versions => {
+                let json = download::decompress(self.name, &mut resp, verifier, Vec::new()).await?;
+                let pkgsjson: HashMap<String, String> = serde_json::from_slice(&json)?;
+                nixos::createdb(self.name, data.tmppath(), &pkgsjson).await?;
+            }
+            Format::Packages => {
+                let json = download::decompress(self.name, &mut resp, verifier, Vec::new()).await?;
+                let pkgjson: NixPkgList = serde_json::from_slice(&json)?;
+                let pkgout = pkgjson
+                    .packages
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.version.to_string()))
+                    .collect::<HashMap<String, String>>();
+                nixos::createdb(self.name, data.tmppath(), &pkgout).await?;
+            }
+        }
+        Ok(Fetched::Done)
+    }
+}
+
+/// Outcome of fetching a single [Source].
+enum Fetched {
+    Done,
+    /// The mirror doesn't have the requested file.
+    Missing,
+    /// The mirror couldn't be reached.
+    Unreachable,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cache::{DownloadError, IntegrityError},
+        NixData,
+    };
+    use std::io::Write;
+    use tempfile::TempDir;
+    use tokio::sync::{Mutex, MutexGuard};
+
+    lazy_static::lazy_static! {
+        /// The context is global, so tests that change it run one at a time.
+        static ref CONTEXT: Mutex<()> = Mutex::new(());
+    }
+
+    /// Cache and config in a temporary directory, with a `file://` mirror publishing a single `test` entry.
+    struct Fixture {
+        dir: TempDir,
+        _context: MutexGuard<'static, ()>,
+    }
+
+    impl Fixture {
+        async fn new(config: &str) -> Fixture {
+            let context = CONTEXT.lock().await;
+            let dir = TempDir::new().unwrap();
+            fs::create_dir_all(dir.path().join("config")).unwrap();
+            fs::create_dir_all(dir.path().join("mirror/test")).unwrap();
+            fs::write(dir.path().join("config/config.json"), config).unwrap();
+            NixData::builder()
+                .cachedir(dir.path().join("cache"))
+                .configdir(dir.path().join("config"))
+                .home(dir.path())
+                .build()
+                .init();
+            Fixture {
+                dir,
+                _context: context,
+            }
+        }
+
+        fn setconfig(&self, config: &str) {
+            fs::write(self.dir.path().join("config/config.json"), config).unwrap();
+        }
+
+        fn mirror(&self) -> String {
+            format!("file://{}/mirror/test", self.dir.path().display())
+        }
+
+        /// Publishes `data` as version `version` on the mirror.
+        fn publish(&self, version: &str, data: &[u8]) {
+            let mut compressed = Vec::new();
+            brotli::CompressorWriter::new(&mut compressed, 4096, 1, 22)
+                .write_all(data)
+                .unwrap();
+            self.publishraw(version, &compressed);
+        }
+
+        /// Publishes `compressed` as is, without compressing it.
+        fn publishraw(&self, version: &str, compressed: &[u8]) {
+            let dir = self.dir.path().join("mirror/test");
+            fs::write(dir.join("test.db.br"), compressed).unwrap();
+            fs::write(dir.join("test.ver"), version).unwrap();
+        }
+
+        fn entry(&self) -> CacheEntry {
+            CacheEntry {
+                name: "test",
+                ext: "db",
+                releases: vec![Release {
+                    version: Version::Url(format!("{}/test.ver", self.mirror())),
+                    sources: vec![Source::Download {
+                        url: format!("{}/test.db.br", self.mirror()),
+                        format: Format::Raw,
+                        verify: true,
+                    }],
+                }],
+            }
+        }
+
+        /// Publishes a checksum manifest listing `hash` for the download.
+        fn publishchecksum(&self, hash: &str) {
+            fs::write(
+                self.dir.path().join("mirror/test/test.sha256"),
+                format!("{}  test.db.br\n", hash),
+            )
+            .unwrap();
+        }
+
+        /// Names of all files in the cache directory, including temporary files.
+        fn cachefiles(&self) -> Vec<String> {
+            let mut files = fs::read_dir(cachedir())
+                .unwrap()
+                .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+                .collect::<Vec<_>>();
+            files.sort();
+            files
+        }
+
+        fn cacheddata(&self) -> Vec<u8> {
+            fs::read(self.entry().path()).unwrap()
+        }
+    }
+
+    fn config(policy: &str) -> String {
+        format!(r#"{{"cachepolicy": {}}}"#, policy)
+    }
+
+    #[tokio::test]
+    async fn downloadsandreusescache() {
+        let fixture = Fixture::new(&config(r#""alwaysrefresh""#)).await;
+        fixture.publish("1", b"first");
+        let file = fixture.entry().get().await.unwrap();
+        assert_eq!(file.path, fixture.entry().path());
+        assert_eq!(file.version.as_deref(), Some("1"));
+        assert!(!file.stale);
+        assert_eq!(fixture.cacheddata(), b"first");
+
+        // An unchanged version is not downloaded again
+        fixture.publishraw("1", b"not brotli");
+        let file = fixture.entry().get().await.unwrap();
+        assert_eq!(file.version.as_deref(), Some("1"));
+        assert!(!file.stale);
+        assert_eq!(fixture.cacheddata(), b"first");
+
+        fixture.publish("2", b"second");
+        let file = fixture.entry().get().await.unwrap();
+        assert_eq!(file.version.as_deref(), Some("2"));
+        assert_eq!(fixture.cacheddata(), b"second");
+    }
+
+    #[tokio::test]
+    async fn keepsoldcacheonchecksummismatch() {
+        let fixture = Fixture::new(&config(r#""alwaysrefresh""#)).await;
+        fixture.publish("1", b"first");
+        fixture.entry().get().await.unwrap();
+
+        fixture.publish("2", b"second");
+        fixture.publishchecksum(&"0".repeat(64));
+        let err = fixture.entry().get().await.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                Error::Integrity(IntegrityError::ChecksumMismatch { .. })
+            ),
+            "{:?}",
+            err
+        );
+        assert_eq!(fixture.entry().cachedversion().as_deref(), Some("1"));
+        assert_eq!(fixture.cacheddata(), b"first");
+        assert_eq!(fixture.cachefiles(), ["test.db", "test.lock", "test.ver"]);
+    }
+
+    #[tokio::test]
+    async fn keepsoldcacheonbrokendownload() {
+        let fixture = Fixture::new(&config(r#""alwaysrefresh""#)).await;
+        fixture.publish("1", b"first");
+        fixture.entry().get().await.unwrap();
+
+        let mut compressed = Vec::new();
+        brotli::CompressorWriter::new(&mut compressed, 4096, 1, 22)
+            .write_all(&[7; 100000])
+            .unwrap();
+        for broken in [&compressed[..compressed.len() / 2], b"not brotli"] {
+            fixture.publishraw("2", broken);
+            let err = fixture.entry().get().await.unwrap_err();
+            assert!(
+                matches!(err, Error::Download(DownloadError::Decompress(_))),
+                "{:?}",
+                err
+            );
+            assert_eq!(fixture.entry().cachedversion().as_deref(), Some("1"));
+            assert_eq!(fixture.cacheddata(), b"first");
+            assert_eq!(fixture.cachefiles(), ["test.db", "test.lock", "test.ver"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn offlineonlyusescache() {
+        let fixture = Fixture::new(&config(r#""offlineonly""#)).await;
+        fixture.publish("1", b"first");
+        let err = fixture.entry().get().await.unwrap_err();
+        assert!(matches!(err, Error::Cache(_)), "{:?}", err);
+        assert!(fixture.cachefiles().iter().all(|f| f == "test.lock"));
+
+        fixture.setconfig(&config(r#""alwaysrefresh""#));
+        fixture.entry().get().await.unwrap();
+        fixture.setconfig(&config(r#""offlineonly""#));
+        fixture.publish("2", b"second");
+        let file = fixture.entry().get().await.unwrap();
+        assert_eq!(file.version.as_deref(), Some("1"));
+        assert!(file.stale);
+        assert_eq!(fixture.cacheddata(), b"first");
+    }
+
+    #[tokio::test]
+    async fn refreshesonlyolderthanage() {
+        let fixture = Fixture::new(&config(r#"{"refreshifolderthan": 3600}"#)).await;
+        fixture.publish("1", b"first");
+        fixture.entry().get().await.unwrap();
+
+        // Checked within the last hour
+        fixture.publish("2", b"second");
+        let file = fixture.entry().get().await.unwrap();
+        assert_eq!(file.version.as_deref(), Some("1"));
+        assert!(!file.stale);
+        assert_eq!(fixture.cacheddata(), b"first");
+
+        let checked = SystemTime::now() - Duration::from_secs(7200);
+        filetime::set_file_mtime(
+            fixture.entry().verpath(),
+            FileTime::from_system_time(checked),
+        )
+        .unwrap();
+        let file = fixture.entry().get().await.unwrap();
+        assert_eq!(file.version.as_deref(), Some("2"));
+        assert_eq!(fixture.cacheddata(), b"second");
+
+        // An unchanged version is checked again only after the age has passed again
+        filetime::set_file_mtime(
+            fixture.entry().verpath(),
+            FileTime::from_system_time(checked),
+        )
+        .unwrap();
+        fixture.entry().get().await.unwrap();
+        assert!(fixture.entry().checkedwithin(Duration::from_secs(60)));
+    }
+
+    #[tokio::test]
+    async fn fallsbackwhenmirrorismissing() {
+        let fixture = Fixture::new("{}").await;
+        fixture.setconfig(&format!(r#"{{"dbmirror": "{}"}}"#, fixture.mirror()));
+        fixture.publish("1", b"first");
+        fixture.entry().get().await.unwrap();
+
+        // A missing file in an existing mirror is not an offline mirror
+        fs::remove_file(fixture.dir.path().join("mirror/test/test.ver")).unwrap();
+        let err = fixture.entry().get().await.unwrap_err();
+        assert!(matches!(err, Error::Cache(_)), "{:?}", err);
+
+        // Such as an unmounted drive
+        fs::remove_dir_all(fixture.dir.path().join("mirror")).unwrap();
+        let file = fixture.entry().get().await.unwrap();
+        assert_eq!(file.version.as_deref(), Some("1"));
+        assert!(file.stale);
+        assert_eq!(fixture.cacheddata(), b"first");
+    }
+}
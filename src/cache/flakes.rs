@@ -1,115 +1,53 @@
-use crate::CACHEDIR;
-use anyhow::{Context, Result};
-use log::info;
-use sqlx::SqlitePool;
+use crate::{context::cachedir, utils, Error, Result};
 use std::{
     collections::{HashMap, HashSet},
-    fs::{self, File},
-    io::{Read, Write},
-    path::Path,
+    fs,
     process::Command,
 };
 
 use super::{
-    nixos::{self, getnixospkgs, nixospkgs},
-    NixPkg,
+    entry::{CacheEntry, Format, Release, Source, Version},
+    mirror,
+    nixos::{self, getnixospkgs},
+    CachedFile,
 };
 
 /// Gets a list of all packages in the NixOS system with their name and version.
 /// Can be used to find what versions of system packages are currently installed.
 /// Will only work on NixOS systems.
-pub async fn flakespkgs() -> Result<String> {
-    let versionout = Command::new("nixos-version").arg("--json").output()?;
-    let version: HashMap<String, String> = serde_json::from_slice(&versionout.stdout)?;
-
+pub async fn flakespkgs() -> Result<CachedFile> {
+    let version = utils::nixosversion()?;
     let nixosversion = version
         .get("nixosVersion")
-        .context("No NixOS version found")?;
-
-    // If cache directory doesn't exist, create it
-    if !std::path::Path::new(&*CACHEDIR).exists() {
-        std::fs::create_dir_all(&*CACHEDIR)?;
-    }
+        .ok_or_else(|| Error::Parse(String::from("No NixOS version found")))?;
 
-    // Check if latest version is already downloaded
-    if let Ok(prevver) = fs::read_to_string(&format!("{}/flakespkgs.ver", &*CACHEDIR)) {
-        if prevver.eq(nixosversion) && Path::new(&format!("{}/flakespkgs.db", &*CACHEDIR)).exists()
-        {
-            info!("No new version of NixOS flakes found");
-            return Ok(format!("{}/flakespkgs.db", &*CACHEDIR));
-        }
-    }
-
-    // Get list of packages from flake
-    let pkgsout = if let Some(rev) = version.get("nixpkgsRevision") {
-        let url = format!("https://raw.githubusercontent.com/snowflakelinux/nixpkgs-version-data/main/nixos-{}/{}.json.br", nixosversion.get(0..5).context("Invalid NixOS version")?, rev);
-        let resp = reqwest::get(&url).await?;
-        if resp.status().is_success() {
-            let r = resp.bytes().await?;
-            let mut br = brotli::Decompressor::new(r.as_ref(), 4096);
-            let mut pkgsout = Vec::new();
-            br.read_to_end(&mut pkgsout)?;
-            let pkgsjson: HashMap<String, String> = serde_json::from_slice(&pkgsout)?;
-            pkgsjson
-        } else {
-            let url = format!("https://raw.githubusercontent.com/snowflakelinux/nixpkgs-version-data/main/nixos-unstable/{}.json.br", rev);
-            let resp = reqwest::get(&url).await?;
-            if resp.status().is_success() {
-                let r = resp.bytes().await?;
-                let mut br = brotli::Decompressor::new(r.as_ref(), 4096);
-                let mut pkgsout = Vec::new();
-                br.read_to_end(&mut pkgsout)?;
-                let pkgsjson: HashMap<String, String> = serde_json::from_slice(&pkgsout)?;
-                pkgsjson
-            } else {
-                let pkgsout = Command::new("nix")
-                    .arg("search")
-                    .arg("--json")
-                    .arg(&format!("nixpkgs/{}", rev))
-                    .output()?;
-                let pkgsjson: HashMap<String, NixPkg> =
-                    serde_json::from_str(&String::from_utf8(pkgsout.stdout)?)?;
-                let pkgsjson = pkgsjson
-                    .iter()
-                    .map(|(k, v)| {
-                        (
-                            k.split('.').collect::<Vec<_>>()[2..].join("."),
-                            v.version.to_string(),
-                        )
-                    })
-                    .collect::<HashMap<String, String>>();
-                pkgsjson
-            }
-        }
-    } else {
-        let pkgsout = Command::new("nix")
-            .arg("search")
-            .arg("--json")
-            // .arg("--inputs-from")
-            // .arg(&flakepath)
-            .arg("nixpkgs")
-            .output()?;
-        let pkgsjson: HashMap<String, NixPkg> =
-            serde_json::from_str(&String::from_utf8(pkgsout.stdout)?)?;
-        let pkgsjson = pkgsjson
+    let mirrors = mirror::mirrors()?;
+    let sources = if let Some(rev) = version.get("nixpkgsRevision") {
+        let relver = utils::nixosrelease(&version)?;
+        let mut sources = [relver, "unstable"]
             .iter()
-            .map(|(k, v)| {
-                (
-                    k.split('.').collect::<Vec<_>>()[2..].join("."),
-                    v.version.to_string(),
-                )
+            .map(|channel| Source::Download {
+                url: format!("{}/nixos-{}/{}.json.br", mirrors.versiondata, channel, rev),
+                format: Format::Versions,
+                verify: true,
             })
-            .collect::<HashMap<String, String>>();
-        pkgsjson
+            .collect::<Vec<_>>();
+        sources.push(Source::NixSearch(format!("nixpkgs/{}", rev)));
+        sources
+    } else {
+        vec![Source::NixSearch(String::from("nixpkgs"))]
     };
 
-    let dbfile = format!("{}/flakespkgs.db", &*CACHEDIR);
-    nixos::createdb(&dbfile, &pkgsout).await?;
-
-    // Write version downloaded to file
-    File::create(format!("{}/flakespkgs.ver", &*CACHEDIR))?.write_all(nixosversion.as_bytes())?;
-
-    Ok(format!("{}/flakespkgs.db", &*CACHEDIR))
+    CacheEntry {
+        name: "flakespkgs",
+        ext: "db",
+        releases: vec![Release {
+            version: Version::Known(nixosversion.to_string()),
+            sources,
+        }],
+    }
+    .get()
+    .await
 }
 
 /// Returns a list of all installed system packages with their attribute and version
@@ -119,19 +57,17 @@ pub async fn getflakepkgs(paths: &[&str]) -> Result<HashMap<String, String>> {
 }
 
 pub fn uptodate() -> Result<Option<(String, String)>> {
-    let flakesver = fs::read_to_string(&format!("{}/flakespkgs.ver", &*CACHEDIR))?;
-    let nixosver = fs::read_to_string(&format!("{}/nixospkgs.ver", &*CACHEDIR))?;
+    let flakesver = fs::read_to_string(format!("{}/flakespkgs.ver", cachedir()))?;
+    let nixosver = fs::read_to_string(format!("{}/nixospkgs.ver", cachedir()))?;
     let flakeslast = flakesver
-        .split('.')
-        .collect::<Vec<_>>()
-        .last()
-        .context("Invalid version")?
+        .rsplit('.')
+        .next()
+        .ok_or_else(|| Error::Parse(String::from("Invalid version")))?
         .to_string();
     let nixoslast = nixosver
-        .split('.')
-        .collect::<Vec<_>>()
-        .last()
-        .context("Invalid version")?
+        .rsplit('.')
+        .next()
+        .ok_or_else(|| Error::Parse(String::from("Invalid version")))?
         .to_string();
     if !nixoslast.starts_with(&flakeslast) {
         Ok(Some((flakesver, nixosver)))
@@ -141,62 +77,40 @@ pub fn uptodate() -> Result<Option<(String, String)>> {
 }
 
 pub async fn unavailablepkgs(paths: &[&str]) -> Result<HashMap<String, String>> {
-    let versionout = Command::new("nixos-version").arg("--json").output()?;
-    let version: HashMap<String, String> = serde_json::from_slice(&versionout.stdout)?;
+    let version = utils::nixosversion()?;
     let nixpath = if let Some(rev) = version.get("nixpkgsRevision") {
-        Command::new("nix")
-            .arg("eval")
-            .arg(&format!("nixpkgs/{}#path", rev))
-            .output()?
-            .stdout
+        utils::output(
+            Command::new("nix")
+                .arg("eval")
+                .arg(format!("nixpkgs/{}#path", rev)),
+        )?
+        .stdout
     } else {
-        Command::new("nix")
-            .arg("eval")
-            .arg("nixpkgs#path")
-            .output()?
-            .stdout
+        utils::output(Command::new("nix").arg("eval").arg("nixpkgs#path"))?.stdout
     };
     let nixpath = String::from_utf8(nixpath)?;
     let nixpath = nixpath.trim();
 
-    let aliases = Command::new("nix-instantiate")
+    let aliases = utils::output(Command::new("nix-instantiate")
         .arg("--eval")
         .arg("-E")
-        .arg(&format!("with import {} {{}}; builtins.attrNames ((self: super: lib.optionalAttrs config.allowAliases (import {}/pkgs/top-level/aliases.nix lib self super)) {{}} {{}})", nixpath, nixpath))
-        .arg("--json")
-        .output()?;
+        .arg(format!("with import {} {{}}; builtins.attrNames ((self: super: lib.optionalAttrs config.allowAliases (import {}/pkgs/top-level/aliases.nix lib self super)) {{}} {{}})", nixpath, nixpath))
+        .arg("--json"))?;
     let aliasstr = String::from_utf8(aliases.stdout)?;
     let aliasesout: HashSet<String> = serde_json::from_str(&aliasstr)?;
 
-    let pkgs = {
-        let mut allpkgs: HashSet<String> = HashSet::new();
-        for path in paths {
-            if let Ok(filepkgs) = nix_editor::read::getarrvals(
-                &fs::read_to_string(path)?,
-                "environment.systemPackages",
-            ) {
-                let filepkgset = filepkgs
-                    .into_iter()
-                    .map(|x| x.strip_prefix("pkgs.").unwrap_or(&x).to_string())
-                    .collect::<HashSet<_>>();
-                allpkgs = allpkgs.union(&filepkgset).map(|x| x.to_string()).collect();
-            }
-        }
-        allpkgs
-    };
+    let pkgs = nixos::readpkgs(paths, "environment.systemPackages")?;
 
     let mut unavailable = HashMap::new();
     for pkg in pkgs {
-        if aliasesout.contains(&pkg) && Command::new("nix-instantiate")
+        if aliasesout.contains(&pkg) && utils::output(Command::new("nix-instantiate")
                 .arg("--eval")
                 .arg("-E")
-                .arg(&format!("with import {} {{}}; builtins.tryEval ((self: super: lib.optionalAttrs config.allowAliases (import {}/pkgs/top-level/aliases.nix lib self super)) {{}} {{}}).{}", nixpath, nixpath, pkg))
-                .output()?.status.success() {
-            let out = Command::new("nix-instantiate")
+                .arg(format!("with import {} {{}}; builtins.tryEval ((self: super: lib.optionalAttrs config.allowAliases (import {}/pkgs/top-level/aliases.nix lib self super)) {{}} {{}}).{}", nixpath, nixpath, pkg)))?.status.success() {
+            let out = utils::output(Command::new("nix-instantiate")
                 .arg("--eval")
                 .arg("-E")
-                .arg(&format!("with import {} {{}}; ((self: super: lib.optionalAttrs config.allowAliases (import {}/pkgs/top-level/aliases.nix lib self super)) {{}} {{}}).{}", nixpath, nixpath, pkg))
-                .output()?;
+                .arg(format!("with import {} {{}}; ((self: super: lib.optionalAttrs config.allowAliases (import {}/pkgs/top-level/aliases.nix lib self super)) {{}} {{}}).{}", nixpath, nixpath, pkg)))?;
             let err = String::from_utf8(out.stderr)?;
             let err = err.strip_prefix("error: ").unwrap_or(&err).trim();
             unavailable.insert(pkg, err.to_string());
@@ -204,25 +118,6 @@ pub async fn unavailablepkgs(paths: &[&str]) -> Result<HashMap<String, String>>
     }
 
     let profilepkgs = getflakepkgs(paths).await?;
-    let nixospkgs = nixospkgs().await?;
-    let pool = SqlitePool::connect(&format!("sqlite://{}", nixospkgs)).await?;
-
-    for (pkg, _) in profilepkgs {
-        let (x, broken, insecure): (String, u8, u8) =
-            sqlx::query_as("SELECT attribute,broken,insecure FROM meta WHERE attribute = $1")
-                .bind(&pkg)
-                .fetch_one(&pool)
-                .await?;
-        if x != pkg {
-            unavailable.insert(
-                pkg,
-                String::from("Package not found in newer version of nixpkgs"),
-            );
-        } else if broken == 1 {
-            unavailable.insert(pkg, String::from("Package is marked as broken"));
-        } else if insecure == 1 {
-            unavailable.insert(pkg, String::from("Package is marked as insecure"));
-        }
-    }
+    nixos::unavailablemeta(&profilepkgs.keys().collect::<Vec<_>>(), &mut unavailable).await?;
     Ok(unavailable)
 }
@@ -0,0 +1,67 @@
+use crate::{config::configfile::getconfigordefault, Result};
+use log::debug;
+use std::{collections::HashMap, path::Path};
+
+use super::{
+    channel,
+    db::PackageDb,
+    entry::{CacheEntry, Format, Release, Source, Version},
+    flakes, mirror,
+    nixos::readpkgs,
+    nonnixos, CachedFile,
+};
+
+const HOMEMANAGER: &str = "github:nix-community/home-manager";
+
+/// Gets the latest Home Manager `options.json` and returns the path to the file.
+/// The file has the same format as [nixosoptions()](super::nixos::nixosoptions),
+/// so it can be opened with [OptionsDb](crate::options::OptionsDb).
+///
+/// If the database mirror publishes `home-manager/options.ver`, `home-manager/options.json.br`
+/// and `home-manager/options.sha256`, the file is downloaded from there.
+/// Otherwise it is built from the `docs-json` package of the Home Manager flake, which requires `nix` with flakes enabled.
+pub async fn homemanageroptions() -> Result<CachedFile> {
+    let mirrors = mirror::mirrors()?;
+    CacheEntry {
+        name: "homemanageroptions",
+        ext: "json",
+        releases: vec![
+            Release {
+                version: Version::Url(format!("{}/home-manager/options.ver", mirrors.db)),
+                sources: vec![Source::Download {
+                    url: format!("{}/home-manager/options.json.br", mirrors.db),
+                    format: Format::Raw,
+                    verify: true,
+                }],
+            },
+            Release {
+                version: Version::Flake(HOMEMANAGER.to_string()),
+                sources: vec![Source::NixBuild {
+                    installable: format!("{}#docs-json", HOMEMANAGER),
+                    path: String::from("share/doc/home-manager/options.json"),
+                }],
+            },
+        ],
+    }
+    .get()
+    .await
+}
+
+/// Returns a list of all packages in `home.packages` with their attribute and version.
+/// The input `paths` should be the paths to the `home.nix` files containing `home.packages`.
+///
+/// Versions are resolved like system packages on NixOS, using the flake versions if a `flake` is set in the [config](crate::config).
+/// On other systems the latest nixpkgs from [nixpkgs()](super::nonnixos::nixpkgs) is used.
+pub async fn gethomepkgs(paths: &[&str]) -> Result<HashMap<String, String>> {
+    let pkgs = readpkgs(paths, "home.packages")?;
+    debug!("gethomepkgs: {:?}", pkgs);
+    let pkgsdb = if !Path::new("/etc/NIXOS").exists() {
+        nonnixos::nixpkgs().await?
+    } else if getconfigordefault()?.flake.is_some() {
+        flakes::flakespkgs().await?
+    } else {
+        channel::legacypkgs().await?
+    };
+    let db = PackageDb::open(pkgsdb).await?;
+    db.versions(&pkgs.into_iter().collect::<Vec<_>>()).await
+}
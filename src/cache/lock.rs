@@ -0,0 +1,63 @@
+use crate::{context::cachedir, Result};
+use fs2::FileExt;
+use log::debug;
+use std::{
+    fs::{self, File},
+    io,
+};
+
+use super::progress::{self, Phase};
+
+/// Exclusive advisory lock on a cache entry, held while it is refreshed.
+/// Other processes refreshing the same entry wait until it is dropped.
+pub(crate) struct CacheLock {
+    _file: File,
+}
+
+impl CacheLock {
+    /// Locks the entry `name`, waiting for other processes holding the lock without blocking the runtime.
+    pub async fn acquire(name: &str) -> Result<CacheLock> {
+        let file = open(name)?;
+        if trylock(&file)? {
+            return Ok(CacheLock { _file: file });
+        }
+        debug!("Waiting for another process to refresh {}", name);
+        progress::report(name, Phase::Waiting, 0, None);
+        let file = tokio::task::spawn_blocking(move || file.lock_exclusive().map(|_| file))
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))??;
+        Ok(CacheLock { _file: file })
+    }
+
+    /// Blocking version of [CacheLock::acquire()].
+    pub fn acquire_blocking(name: &str) -> Result<CacheLock> {
+        let file = open(name)?;
+        if !trylock(&file)? {
+            debug!("Waiting for another process to refresh {}", name);
+            progress::report(name, Phase::Waiting, 0, None);
+            file.lock_exclusive()?;
+        }
+        Ok(CacheLock { _file: file })
+    }
+}
+
+/// Opens the lock file of the entry `name`.
+/// Lock files are never removed, as a process could be waiting on the old file while another one creates a new one.
+fn open(name: &str) -> Result<File> {
+    // If cache directory doesn't exist, create it
+    let dir = cachedir();
+    fs::create_dir_all(&dir)?;
+    Ok(File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(format!("{}/{}.lock", dir, name))?)
+}
+
+fn trylock(file: &File) -> Result<bool> {
+    match file.try_lock_exclusive() {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == fs2::lock_contended_error().kind() => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
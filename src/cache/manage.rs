@@ -0,0 +1,178 @@
+use crate::{context::cachedir, Error, Result};
+use log::debug;
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use super::lock::CacheLock;
+
+/// Information about an entry in the cache directory, such as `nixospkgs`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheInfo {
+    /// Name of the entry, such as `nixospkgs` or `nixosoptions`.
+    pub name: String,
+    /// Path to the cached data, if it exists.
+    pub path: Option<String>,
+    /// Version the data was fetched for, read from the `.ver` file.
+    pub version: Option<String>,
+    /// Combined size in bytes of all files belonging to the entry.
+    pub size: u64,
+    /// When the data was last written.
+    pub modified: Option<SystemTime>,
+}
+
+impl CacheInfo {
+    /// Whether the entry is missing either its data or its `.ver` file.
+    pub fn isorphaned(&self) -> bool {
+        self.path.is_none() || self.version.is_none()
+    }
+}
+
+/// Files in the cache directory that belong to one entry.
+#[derive(Default)]
+struct Files {
+    data: Option<PathBuf>,
+    ver: Option<PathBuf>,
+    /// Temporary files of unfinished downloads and SQLite journals.
+    other: Vec<PathBuf>,
+}
+
+/// Groups the files in the cache directory by the entry they belong to.
+fn entries() -> Result<BTreeMap<String, Files>> {
+    let mut out: BTreeMap<String, Files> = BTreeMap::new();
+    let dir = cachedir();
+    if !Path::new(&dir).exists() {
+        return Ok(out);
+    }
+    for file in fs::read_dir(&dir)?.flatten() {
+        if !file.file_type()?.is_file() {
+            continue;
+        }
+        let filename = file.file_name().to_string_lossy().to_string();
+        let (name, ext) = match filename.split_once('.') {
+            Some(x) => x,
+            None => continue,
+        };
+        // Lock files must stay in place while any process could be using them
+        if ext == "lock" {
+            continue;
+        }
+        let files = out.entry(name.to_string()).or_default();
+        if ext == "ver" {
+            files.ver = Some(file.path());
+        } else if ext.contains(".tmp") || ext.contains('-') {
+            files.other.push(file.path());
+        } else {
+            files.data = Some(file.path());
+        }
+    }
+    Ok(out)
+}
+
+/// Lists all entries in the cache directory.
+pub fn list() -> Result<Vec<CacheInfo>> {
+    let mut out = vec![];
+    for (name, files) in entries()? {
+        let mut size = 0;
+        for path in files.data.iter().chain(&files.ver).chain(&files.other) {
+            size += ignoremissing(fs::metadata(path))?.map_or(0, |m| m.len());
+        }
+        let modified = match &files.data {
+            Some(path) => ignoremissing(fs::metadata(path))?.and_then(|m| m.modified().ok()),
+            None => None,
+        };
+        let version = match &files.ver {
+            Some(path) => ignoremissing(fs::read_to_string(path))?,
+            None => None,
+        };
+        out.push(CacheInfo {
+            name,
+            path: files.data.map(|p| p.to_string_lossy().to_string()),
+            version,
+            size,
+            modified,
+        });
+    }
+    Ok(out)
+}
+
+/// Returns `None` if the file was removed after the cache directory was read,
+/// such as a temporary file of a download that just finished.
+fn ignoremissing<T>(result: io::Result<T>) -> Result<Option<T>> {
+    match result {
+        Ok(x) => Ok(Some(x)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Removes all files belonging to the entry `name`.
+/// The entry will be downloaded again the next time it is used.
+/// Waits for any process that is currently refreshing the entry.
+pub fn purge(name: &str) -> Result<()> {
+    let _lock = CacheLock::acquire_blocking(name)?;
+    let files = entries()?
+        .remove(name)
+        .ok_or_else(|| Error::Cache(format!("No cache entry named {}", name)))?;
+    for path in files.data.iter().chain(&files.ver).chain(&files.other) {
+        debug!("Removing {}", path.display());
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Removes all entries from the cache directory.
+pub fn purgeall() -> Result<()> {
+    for name in entries()?.keys() {
+        purge(name)?;
+    }
+    Ok(())
+}
+
+/// Removes `.ver` files without data, data without a `.ver` file
+/// and temporary files left behind by interrupted downloads.
+/// Returns the paths of the removed files.
+/// Waits for any process that is currently refreshing an entry, as it may have written its data but not yet its `.ver` file.
+pub fn removeorphans() -> Result<Vec<String>> {
+    let mut removed = vec![];
+    for name in entries()?.into_keys() {
+        let _lock = CacheLock::acquire_blocking(&name)?;
+        // Read the files again, as they may have changed while waiting for the lock
+        let files = match entries()?.remove(&name) {
+            Some(files) => files,
+            None => continue,
+        };
+        let nodata = files.data.is_none() || files.ver.is_none();
+        let mut orphans = files
+            .other
+            .into_iter()
+            .filter(|p| match tmppid(p) {
+                Some(pid) => !Path::new(&format!("/proc/{}", pid)).exists(),
+                // SQLite journals belong to their database
+                None => nodata,
+            })
+            .collect::<Vec<_>>();
+        match (files.data, files.ver) {
+            (Some(data), None) => orphans.push(data),
+            (None, Some(ver)) => orphans.push(ver),
+            _ => {}
+        }
+        for path in orphans {
+            debug!("Removing orphaned {}", path.display());
+            fs::remove_file(&path)?;
+            removed.push(path.to_string_lossy().to_string());
+        }
+    }
+    Ok(removed)
+}
+
+/// Process id of the process writing the temporary file at `path`, if it is one.
+fn tmppid(path: &Path) -> Option<u32> {
+    let filename = path.file_name()?.to_string_lossy();
+    let (_, pid) = filename.rsplit_once(".tmp")?;
+    let end = pid.find(|c: char| !c.is_ascii_digit()).unwrap_or(pid.len());
+    pid[..end].parse().ok()
+}
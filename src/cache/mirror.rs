@@ -0,0 +1,170 @@
+use crate::{config::configfile::getconfigordefault, Error, Result};
+use log::warn;
+use reqwest::Url;
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    path::PathBuf,
+};
+
+static DBMIRROR: &str = "https://raw.githubusercontent.com/snowflakelinux/nix-data-db/main";
+static VERSIONMIRROR: &str =
+    "https://raw.githubusercontent.com/snowflakelinux/nixpkgs-version-data/main";
+static CHANNELMIRROR: &str = "https://channels.nixos.org";
+static RELEASEMIRROR: &str = "https://releases.nixos.org";
+
+/// Base URLs of all remote locations the cache downloads from.
+pub(crate) struct Mirrors {
+    pub db: String,
+    pub versiondata: String,
+    pub channels: String,
+    pub releases: String,
+}
+
+/// Returns the mirrors set in the config file, falling back to the upstream defaults.
+/// Errors if the config file is invalid.
+pub(crate) fn mirrors() -> Result<Mirrors> {
+    let config = getconfigordefault()?;
+    let base = |url: Option<String>, default: &str| {
+        url.unwrap_or_else(|| default.to_string())
+            .trim_end_matches('/')
+            .to_string()
+    };
+    Ok(Mirrors {
+        db: base(config.dbmirror, DBMIRROR),
+        versiondata: base(config.versionmirror, VERSIONMIRROR),
+        channels: base(config.channelmirror, CHANNELMIRROR),
+        releases: base(config.releasemirror, RELEASEMIRROR),
+    })
+}
+
+enum Body {
+    Http(Box<reqwest::Response>),
+    File(PathBuf, Option<File>),
+}
+
+/// Response from a mirror, either over HTTP(S) or from a local `file://` path.
+pub(crate) struct MirrorResponse {
+    /// Whether the request succeeded. Missing files in an existing local mirror are treated like a `404`.
+    pub success: bool,
+    body: Body,
+}
+
+impl MirrorResponse {
+    /// Size of the body in bytes, if known.
+    pub fn contentlength(&self) -> Option<u64> {
+        match &self.body {
+            Body::Http(resp) => resp.content_length(),
+            Body::File(path, _) => fs::metadata(path).ok().map(|m| m.len()),
+        }
+    }
+
+    /// Reads the next chunk of the body. Returns `None` once the whole body has been read.
+    pub async fn chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        match &mut self.body {
+            Body::Http(resp) => Ok(resp.chunk().await?.map(|b| b.to_vec())),
+            Body::File(path, file) => {
+                let file = match file {
+                    Some(f) => f,
+                    None => file.insert(File::open(path)?),
+                };
+                let mut buf = vec![0u8; 64 * 1024];
+                let size = file.read(&mut buf)?;
+                if size == 0 {
+                    Ok(None)
+                } else {
+                    buf.truncate(size);
+                    Ok(Some(buf))
+                }
+            }
+        }
+    }
+
+    pub async fn bytes(self) -> Result<Vec<u8>> {
+        match self.body {
+            Body::Http(resp) => Ok(resp.bytes().await?.to_vec()),
+            Body::File(path, _) => Ok(fs::read(path)?),
+        }
+    }
+
+    pub async fn text(self) -> Result<String> {
+        Ok(String::from_utf8(self.bytes().await?)?)
+    }
+}
+
+/// Resolves a `file://` URL to a local response. Returns `None` for any other scheme.
+fn getfile(url: &str) -> Result<Option<MirrorResponse>> {
+    let invalid = || Error::Config(format!("Invalid mirror URL: {}", url));
+    let parsed = Url::parse(url).map_err(|_| invalid())?;
+    if parsed.scheme() != "file" {
+        return Ok(None);
+    }
+    let path = parsed.to_file_path().map_err(|_| invalid())?;
+    let resp = match fs::canonicalize(&path) {
+        Ok(realpath) => MirrorResponse {
+            success: realpath.is_file(),
+            body: Body::File(realpath, None),
+        },
+        Err(e) => {
+            // A missing mirror directory, such as an unmounted drive, means the mirror is unreachable
+            if let Some(root) = mirrorroot(url)?.filter(|root| !root.exists()) {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("Mirror {} not found", root.display()),
+                )
+                .into());
+            }
+            MirrorResponse {
+                success: false,
+                body: Body::File(path, None),
+            }
+        }
+    };
+    Ok(Some(resp))
+}
+
+/// Local directory of the configured `file://` mirror that `url` belongs to.
+fn mirrorroot(url: &str) -> Result<Option<PathBuf>> {
+    let mirrors = mirrors()?;
+    Ok([
+        mirrors.db,
+        mirrors.versiondata,
+        mirrors.channels,
+        mirrors.releases,
+    ]
+    .iter()
+    .filter(|mirror| url.starts_with(&format!("{}/", mirror)))
+    .find_map(|mirror| Url::parse(mirror).ok()?.to_file_path().ok()))
+}
+
+/// Sends a GET request to `url`. Errors only if the mirror could not be reached at all.
+pub(crate) async fn get(url: &str) -> Result<MirrorResponse> {
+    if let Some(resp) = getfile(url)? {
+        return Ok(resp);
+    }
+    let client = reqwest::Client::builder().brotli(false).build()?;
+    let resp = client.get(url).send().await.map_err(|e| {
+        if e.is_builder() {
+            Error::Config(format!("Invalid mirror URL: {}", url))
+        } else {
+            e.into()
+        }
+    })?;
+    Ok(MirrorResponse {
+        success: resp.status().is_success(),
+        body: Body::Http(Box::new(resp)),
+    })
+}
+
+/// Sends a GET request to `url` like [get()], but returns `None` if the mirror can't be reached,
+/// so callers can fall back to cached data. Other errors, such as an invalid mirror URL, are returned.
+pub(crate) async fn tryget(url: &str) -> Result<Option<MirrorResponse>> {
+    match get(url).await {
+        Ok(resp) => Ok(Some(resp)),
+        Err(e @ (Error::Network(_) | Error::Io(_))) => {
+            warn!("Failed to reach {}: {}", url, e);
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
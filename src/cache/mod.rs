@@ -1,18 +1,60 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt, path::Path};
 
 use ijson::IString;
 use serde::{Deserialize, Serialize};
 
+pub(crate) mod atomic;
+pub(crate) mod builddb;
 /// Cache and determine packages installed on legacy NixOS and with `nix-env`
 pub mod channel;
+/// Query cached package databases
+pub mod db;
+mod download;
+mod entry;
 /// Cache and determine packages installed on flakes enabled NixOS
 pub mod flakes;
+/// Cache Home Manager options and determine packages installed with Home Manager
+pub mod homemanager;
+pub(crate) mod lock;
+/// List and clear cached files
+pub mod manage;
+mod mirror;
 /// Cache latest NixOS `packages.json` and `options.json`
 pub mod nixos;
-/// Cache and determine packages installed with `nix profile`
-pub mod profile;
 /// Nixpkgs cache on non-NixOS
 pub mod nonnixos;
+/// Cache and determine packages installed with `nix profile`
+pub mod profile;
+/// Report progress of cache refreshes
+pub mod progress;
+mod verify;
+
+pub use download::DownloadError;
+pub use verify::IntegrityError;
+
+/// A file in the cache directory returned by the functions in this module.
+/// Formats as its path, so it can be used directly in `sqlite://` URLs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachedFile {
+    pub path: String,
+    /// Version the data was fetched for, if known.
+    pub version: Option<String>,
+    /// Whether the data couldn't be checked against the latest version,
+    /// because the mirror was unreachable or the [CachePolicy](crate::config::configfile::CachePolicy) is offline-only.
+    pub stale: bool,
+}
+
+impl fmt::Display for CachedFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path)
+    }
+}
+
+impl AsRef<Path> for CachedFile {
+    fn as_ref(&self) -> &Path {
+        Path::new(&self.path)
+    }
+}
 
 #[derive(Debug, Deserialize)]
 struct NixPkgList {
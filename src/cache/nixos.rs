@@ -1,175 +1,79 @@
-use crate::CACHEDIR;
-use anyhow::{anyhow, Context, Result};
-use log::{debug, info};
-use sqlx::{migrate::MigrateDatabase, Row, Sqlite, SqlitePool};
+use crate::{utils, Result};
+use log::debug;
+use sqlx::Connection;
 use std::{
     collections::{HashMap, HashSet},
-    fs::{self, File},
-    io::{Read, Write},
+    fs,
     path::Path,
-    process::{Command, Stdio},
 };
 
-use super::{channel, flakes};
+use super::{
+    builddb, channel,
+    db::PackageDb,
+    entry::{CacheEntry, Format, Release, Source, Version},
+    flakes, mirror, CachedFile,
+};
 
 /// Downloads the latest `packages.json` for the system from the NixOS cache and returns the path to an SQLite database `nixospkgs.db` which contains package data.
 /// Will only work on NixOS systems.
-pub async fn nixospkgs() -> Result<String> {
-    let versionout = Command::new("nixos-version").output()?;
-    let mut version = &String::from_utf8(versionout.stdout)?[0..5];
-
-    // If cache directory doesn't exist, create it
-    if !std::path::Path::new(&*CACHEDIR).exists() {
-        std::fs::create_dir_all(&*CACHEDIR)?;
+pub async fn nixospkgs() -> Result<CachedFile> {
+    let nixosversion = utils::nixosversion()?;
+    let version = utils::nixosrelease(&nixosversion)?;
+
+    let mirrors = mirror::mirrors()?;
+    CacheEntry {
+        name: "nixospkgs",
+        ext: "db",
+        releases: [version, "unstable"]
+            .iter()
+            .map(|v| Release {
+                version: Version::Url(format!("{}/nixos-{}/nixpkgs.ver", mirrors.db, v)),
+                sources: vec![Source::Download {
+                    url: format!("{}/nixos-{}/nixpkgs.db.br", mirrors.db, v),
+                    format: Format::Raw,
+                    verify: true,
+                }],
+            })
+            .collect(),
     }
-
-    let verurl = format!(
-        "https://raw.githubusercontent.com/snowflakelinux/nix-data-db/main/nixos-{}/nixpkgs.ver",
-        version
-    );
-    debug!("Checking NixOS version");
-    let resp = reqwest::get(&verurl);
-    let resp = if let Ok(r) = resp.await {
-        r
-    } else {
-        // Internet connection failed
-        // Check if we can use the old database
-        let dbpath = format!("{}/nixospkgs.db", &*CACHEDIR);
-        if Path::new(&dbpath).exists() {
-            info!("Using old database");
-            return Ok(dbpath);
-        } else {
-            return Err(anyhow!("Could not find latest NixOS version"));
-        }
-    };
-    let latestnixosver = if resp.status().is_success() {
-        resp.text().await?
-    } else {
-        let resp = reqwest::get("https://raw.githubusercontent.com/snowflakelinux/nix-data-db/main/nixos-unstable/nixpkgs.ver").await?;
-        if resp.status().is_success() {
-            version = "unstable";
-            resp.text().await?
-        } else {
-            return Err(anyhow!("Could not find latest NixOS version"));
-        }
-    };
-    debug!("Latest NixOS version: {}", latestnixosver);
-
-    let latestnixosver = latestnixosver
-        .strip_prefix("nixos-")
-        .unwrap_or(&latestnixosver);
-    info!("latestnixosver: {}", latestnixosver);
-    // Check if latest version is already downloaded
-    if let Ok(prevver) = fs::read_to_string(&format!("{}/nixospkgs.ver", &*CACHEDIR)) {
-        if prevver == latestnixosver && Path::new(&format!("{}/nixospkgs.db", &*CACHEDIR)).exists()
-        {
-            debug!("No new version of NixOS found");
-            return Ok(format!("{}/nixospkgs.db", &*CACHEDIR));
-        }
-    }
-
-    let url = format!(
-        "https://raw.githubusercontent.com/snowflakelinux/nix-data-db/main/nixos-{}/nixpkgs.db.br",
-        version
-    );
-    debug!("Downloading nix-data database");
-    let client = reqwest::Client::builder().brotli(true).build()?;
-    let resp = client.get(url).send().await?;
-    if resp.status().is_success() {
-        debug!("Writing nix-data database");
-        let mut out = File::create(&format!("{}/nixospkgs.db", &*CACHEDIR))?;
-        {
-            let bytes = resp.bytes().await?;
-            let mut reader = brotli::Decompressor::new(
-                bytes.as_ref(),
-                4096, // buffer size
-            );
-            let mut buf = [0u8; 4096];
-            loop {
-                match reader.read(&mut buf[..]) {
-                    Err(e) => {
-                        if let std::io::ErrorKind::Interrupted = e.kind() {
-                            continue;
-                        }
-                        panic!("{}", e);
-                    }
-                    Ok(size) => {
-                        if size == 0 {
-                            break;
-                        }
-                        if let Err(e) = out.write_all(&buf[..size]) {
-                            panic!("{}", e)
-                        }
-                    }
-                }
-            }
-        }
-        debug!("Writing nix-data version");
-        // Write version downloaded to file
-        File::create(format!("{}/nixospkgs.ver", &*CACHEDIR))?
-            .write_all(latestnixosver.as_bytes())?;
-    } else {
-        return Err(anyhow!("Failed to download latest nixospkgs.db.br"));
-    }
-    Ok(format!("{}/nixospkgs.db", &*CACHEDIR))
+    .get()
+    .await
 }
 
-/// Downloads the latest 'options.json' for the system from the NixOS cache and returns the path to the file.
+/// Downloads the latest `options.json` for the system from the NixOS channels and returns the path to the file.
+/// The cached file is reused while the `git-revision` of the channel is unchanged, and returned as stale if the channel can't be reached.
 /// Will only work on NixOS systems.
-pub fn nixosoptions() -> Result<String> {
-    let versionout = Command::new("nixos-version").output()?;
-    let mut version = &String::from_utf8(versionout.stdout)?[0..5];
-
-    // If cache directory doesn't exist, create it
-    if !std::path::Path::new(&*CACHEDIR).exists() {
-        std::fs::create_dir_all(&*CACHEDIR)?;
-    }
-
-    let verurl = format!("https://channels.nixos.org/nixos-{}", version);
-    debug!("Checking NixOS version");
-    let resp = reqwest::blocking::get(&verurl)?;
-    let latestnixosver = if resp.status().is_success() {
-        resp.url()
-            .path_segments()
-            .context("No path segments found")?
-            .last()
-            .context("Last element not found")?
-            .to_string()
-    } else {
-        let resp = reqwest::blocking::get("https://channels.nixos.org/nixos-unstable")?;
-        if resp.status().is_success() {
-            version = "unstable";
-            resp.url()
-                .path_segments()
-                .context("No path segments found")?
-                .last()
-                .context("Last element not found")?
-                .to_string()
-        } else {
-            return Err(anyhow!("Could not find latest NixOS version"));
-        }
-    };
-    debug!("Latest NixOS version: {}", latestnixosver);
-
-    let url = format!(
-        "https://channels.nixos.org/nixos-{}/options.json.br",
-        version
-    );
-
-    // Download file with reqwest blocking
-    let client = reqwest::blocking::Client::builder().brotli(true).build()?;
-    let mut resp = client.get(url).send()?;
-    if resp.status().is_success() {
-        let mut out = File::create(&format!("{}/nixosoptions.json", &*CACHEDIR))?;
-        resp.copy_to(&mut out)?;
-        // Write version downloaded to file
-        File::create(format!("{}/nixosoptions.ver", &*CACHEDIR))?
-            .write_all(latestnixosver.as_bytes())?;
-    } else {
-        return Err(anyhow!("Failed to download latest options.json"));
+pub async fn nixosoptions() -> Result<CachedFile> {
+    let nixosversion = utils::nixosversion()?;
+    let version = utils::nixosrelease(&nixosversion)?;
+
+    let mirrors = mirror::mirrors()?;
+    CacheEntry {
+        name: "nixosoptions",
+        ext: "json",
+        releases: [version, "unstable"]
+            .iter()
+            .map(|v| Release {
+                version: Version::Url(format!("{}/nixos-{}/git-revision", mirrors.channels, v)),
+                sources: vec![Source::Download {
+                    url: format!("{}/nixos-{}/options.json.br", mirrors.channels, v),
+                    format: Format::Raw,
+                    verify: false,
+                }],
+            })
+            .collect(),
     }
+    .get()
+    .await
+}
 
-    Ok(format!("{}/nixosoptions.json", &*CACHEDIR))
+/// Blocking version of [nixosoptions()] for callers without an async runtime.
+/// Panics if called from within one, use [nixosoptions()] there instead.
+pub fn nixosoptions_blocking() -> Result<CachedFile> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(nixosoptions())
 }
 
 pub(super) enum NixosType {
@@ -181,54 +85,59 @@ pub(super) async fn getnixospkgs(
     paths: &[&str],
     nixos: NixosType,
 ) -> Result<HashMap<String, String>> {
-    let pkgs = {
-        let mut allpkgs: HashSet<String> = HashSet::new();
-        for path in paths {
-            if let Ok(filepkgs) = nix_editor::read::getarrvals(
-                &fs::read_to_string(path)?,
-                "environment.systemPackages",
-            ) {
-                let filepkgset = filepkgs
-                    .into_iter()
-                    .map(|x| x.strip_prefix("pkgs.").unwrap_or(&x).to_string())
-                    .collect::<HashSet<_>>();
-                allpkgs = allpkgs.union(&filepkgset).map(|x| x.to_string()).collect();
-            }
-        }
-        allpkgs
-    };
+    let pkgs = readpkgs(paths, "environment.systemPackages")?;
     debug!("getnixospkgs: {:?}", pkgs);
     let pkgsdb = match nixos {
         NixosType::Flake => flakes::flakespkgs().await?,
         NixosType::Legacy => channel::legacypkgs().await?,
     };
-    let mut out = HashMap::new();
-    let pool = SqlitePool::connect(&format!("sqlite://{}", pkgsdb)).await?;
-    for pkg in pkgs {
-        let mut sqlout = sqlx::query(
-            r#"
-            SELECT version FROM pkgs WHERE attribute = $1
-            "#,
-        )
-        .bind(&pkg)
-        .fetch_all(&pool)
-        .await?;
-        if sqlout.len() == 1 {
-            let row = sqlout.pop().unwrap();
-            let version: String = row.get("version");
-            out.insert(pkg, version);
+    let db = PackageDb::open(pkgsdb).await?;
+    db.versions(&pkgs.into_iter().collect::<Vec<_>>()).await
+}
+
+/// Reads the attributes of all packages in the list `key`, such as `environment.systemPackages`, from the Nix files at `paths`.
+pub(super) fn readpkgs(paths: &[&str], key: &str) -> Result<HashSet<String>> {
+    let mut allpkgs: HashSet<String> = HashSet::new();
+    for path in paths {
+        if let Ok(filepkgs) = nix_editor::read::getarrvals(&fs::read_to_string(path)?, key) {
+            allpkgs.extend(
+                filepkgs
+                    .into_iter()
+                    .map(|x| x.strip_prefix("pkgs.").unwrap_or(&x).to_string()),
+            );
         }
     }
-    Ok(out)
+    Ok(allpkgs)
 }
 
-pub(super) async fn createdb(dbfile: &str, pkgjson: &HashMap<String, String>) -> Result<()> {
-    let db = format!("sqlite://{}", dbfile);
-    if Path::new(dbfile).exists() {
-        fs::remove_file(dbfile)?;
+/// Adds packages from `pkgs` that are missing, broken or insecure in the latest nixpkgs to `unavailable`.
+pub(super) async fn unavailablemeta<S: AsRef<str>>(
+    pkgs: &[S],
+    unavailable: &mut HashMap<String, String>,
+) -> Result<()> {
+    let db = PackageDb::open(nixospkgs().await?).await?;
+    let meta = db.meta(pkgs).await?;
+    for pkg in pkgs {
+        let pkg = pkg.as_ref();
+        let reason = match meta.get(pkg) {
+            None => "Package not found in newer version of nixpkgs",
+            Some(m) if m.broken => "Package is marked as broken",
+            Some(m) if m.insecure => "Package is marked as insecure",
+            Some(_) => continue,
+        };
+        unavailable.insert(pkg.to_string(), String::from(reason));
     }
-    Sqlite::create_database(&db).await?;
-    let pool = SqlitePool::connect(&db).await?;
+    Ok(())
+}
+
+/// Creates a new package database at `dbfile`. Meant to be called on the temporary path of an [AtomicFile](super::atomic::AtomicFile).
+pub(super) async fn createdb(
+    entry: &str,
+    dbfile: &Path,
+    pkgjson: &HashMap<String, String>,
+) -> Result<()> {
+    let mut conn = builddb::create(dbfile).await?;
+    let mut tx = conn.begin().await?;
     sqlx::query(
         r#"
             CREATE TABLE "pkgs" (
@@ -238,29 +147,28 @@ pub(super) async fn createdb(dbfile: &str, pkgjson: &HashMap<String, String>) ->
             )
             "#,
     )
-    .execute(&pool)
+    .execute(&mut *tx)
+    .await?;
+
+    let pkgs = pkgjson.iter().collect::<Vec<_>>();
+    builddb::insert(
+        &mut tx,
+        entry,
+        "pkgs",
+        &["attribute", "version"],
+        &pkgs,
+        |query, (pkg, version)| Ok(query.bind(pkg.as_str()).bind(version.as_str())),
+    )
     .await?;
+
     sqlx::query(
         r#"
         CREATE UNIQUE INDEX "attributes" ON "pkgs" ("attribute")
         "#,
     )
-    .execute(&pool)
+    .execute(&mut *tx)
     .await?;
-
-    let mut wtr = csv::Writer::from_writer(vec![]);
-    for (pkg, version) in pkgjson {
-        wtr.serialize((pkg.to_string(), version.to_string()))?;
-    }
-    let data = String::from_utf8(wtr.into_inner()?)?;
-    let mut cmd = Command::new("sqlite3")
-        .arg("-csv")
-        .arg(dbfile)
-        .arg(".import '|cat -' pkgs")
-        .stdin(Stdio::piped())
-        .spawn()?;
-    let cmd_stdin = cmd.stdin.as_mut().unwrap();
-    cmd_stdin.write_all(data.as_bytes())?;
-    let _status = cmd.wait()?;
+    tx.commit().await?;
+    conn.close().await?;
     Ok(())
 }
@@ -1,80 +1,284 @@
 use crate::{
-    cache::nixos,
-    CACHEDIR,
+    context::{cachedir, home, statehome},
+    storepath::StorePath,
+    utils, Error, Result,
 };
-use anyhow::{anyhow, Context, Result};
-use log::{debug, error, info};
+use log::info;
 use serde::Deserialize;
-use sqlx::SqlitePool;
 use std::{
     collections::{HashMap, HashSet},
     fs::{self, File},
-    io::{Read, Write},
-    path::Path,
+    path::{Path, PathBuf},
     process::Command,
 };
 
-use super::nixos::nixospkgs;
+use super::{
+    db::PackageDb,
+    entry::{CacheEntry, Format, Release, Source, Version},
+    mirror, nixos, CachedFile,
+};
+
+/// Newest `manifest.json` version that can be read.
+const MANIFESTVERSION: u64 = 3;
 
 #[derive(Debug, Deserialize)]
 struct ProfilePkgsRoot {
-    elements: Vec<ProfilePkgOut>,
+    #[serde(default)]
+    version: u64,
+    elements: ProfileElements,
+}
+
+/// Elements are a list up to manifest version 2 and a map keyed by name since version 3.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ProfileElements {
+    List(Vec<ProfilePkgOut>),
+    Map(HashMap<String, ProfilePkgOut>),
 }
 
 #[derive(Debug, Deserialize)]
 struct ProfilePkgOut {
     #[serde(rename = "attrPath")]
     attrpath: Option<String>,
-    #[serde(rename = "originalUrl")]
+    #[serde(rename = "originalUrl", alias = "originalUri")]
     originalurl: Option<String>,
+    #[serde(alias = "uri")]
+    url: Option<String>,
     #[serde(rename = "storePaths")]
     storepaths: Vec<String>,
+    #[serde(default = "defaultactive")]
+    active: bool,
+    #[serde(default = "defaultpriority")]
+    priority: i64,
+}
+
+fn defaultactive() -> bool {
+    true
+}
+
+/// Priority Nix uses for elements without one.
+fn defaultpriority() -> i64 {
+    5
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct FlakeMetadataOut {
+    #[serde(rename = "lastModified")]
+    lastmodified: Option<u64>,
+    revision: Option<String>,
+    locked: Option<FlakeLockedOut>,
+}
+
+impl FlakeMetadataOut {
+    /// Revision the flake reference resolved to.
+    pub(super) fn rev(&self) -> Option<&str> {
+        self.revision
+            .as_deref()
+            .or_else(|| self.locked.as_ref().and_then(|l| l.rev.as_deref()))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct FlakeLockedOut {
+    rev: Option<String>,
 }
 
 /// Struct containing information about a package installed with `nix profile`.
 #[derive(Debug)]
 pub struct ProfilePkg {
+    /// Name of the store path, such as `hello-2.12.1`.
     pub name: String,
+    /// First store path of the element.
+    pub storepath: StorePath,
     pub originalurl: String,
+    /// Locked flake reference the element was installed from, such as `github:NixOS/nixpkgs/<rev>`.
+    pub url: Option<String>,
+    /// Revision of the flake the element was installed from.
+    pub lockedrev: Option<String>,
+    /// Name of the element in the profile, as used by `nix profile remove`.
+    pub elementname: String,
+    /// Priority used to resolve file conflicts between elements. Lower values win.
+    pub priority: i64,
+    /// Whether the element is linked into the profile.
+    pub active: bool,
+}
+
+/// Returns the default profile of the user.
+/// This is `~/.nix-profile`, or `$XDG_STATE_HOME/nix/profile` if Nix is set to `use-xdg-base-directories`.
+/// Returns `None` if there is no profile.
+pub fn defaultprofile() -> Result<Option<PathBuf>> {
+    for link in [
+        format!("{}/.nix-profile", home()?),
+        format!("{}/nix/profile", statehome()?),
+    ] {
+        if fs::metadata(&link).is_ok() {
+            return Ok(Some(PathBuf::from(link)));
+        }
+    }
+    Ok(None)
+}
+
+/// Returns all profiles the current user can read, such as their own profiles,
+/// profiles of other users under `/nix/var/nix/profiles/per-user`
+/// and profiles created elsewhere with `--profile`, which Nix registers as garbage collector roots.
+/// Each profile is returned once, as the last symlink before its generations.
+///
+/// Only package profiles managed by `nix profile` or `nix-env` are returned.
+/// Other profiles, such as the NixOS `system` profile, Home Manager profiles and `channels` profiles, are skipped.
+pub fn profiles() -> Result<Vec<PathBuf>> {
+    let mut candidates = vec![];
+    if let Some(profile) = defaultprofile()? {
+        candidates.push(profile);
+    }
+    let mut dirs = vec![
+        PathBuf::from(format!("{}/nix/profiles", statehome()?)),
+        PathBuf::from("/nix/var/nix/profiles"),
+    ];
+    if let Ok(users) = fs::read_dir("/nix/var/nix/profiles/per-user") {
+        dirs.extend(users.flatten().map(|user| user.path()));
+    }
+    for dir in dirs {
+        if let Ok(entries) = fs::read_dir(dir) {
+            candidates.extend(entries.flatten().map(|entry| entry.path()));
+        }
+    }
+    // Generations of profiles outside of the profile directories are indirect roots
+    if let Ok(roots) = fs::read_dir("/nix/var/nix/gcroots/auto") {
+        for root in roots.flatten() {
+            if let Ok(generation) = fs::read_link(root.path()) {
+                if let Some(profile) = generationprofile(&generation) {
+                    candidates.push(profile);
+                }
+            }
+        }
+    }
+
+    let mut out = candidates
+        .iter()
+        .filter(|path| generationprofile(path).is_none())
+        .filter_map(|path| profilelink(path))
+        .filter(|profile| ispkgprofile(profile))
+        .collect::<Vec<_>>();
+    out.sort();
+    out.dedup();
+    Ok(out)
+}
+
+/// Whether `profile` contains packages installed with `nix profile` or `nix-env`.
+/// Channels are installed with `nix-env` as well, so `channels` profiles are excluded.
+fn ispkgprofile(profile: &Path) -> bool {
+    !profile.ends_with("channels")
+        && ["manifest.json", "manifest.nix"]
+            .iter()
+            .any(|manifest| profile.join(manifest).exists())
+}
+
+/// Follows symlinks from `link` to the profile it points to,
+/// the last symlink before a generation such as `profile-5-link`.
+fn profilelink(link: &Path) -> Option<PathBuf> {
+    let mut current = link.to_path_buf();
+    // Bounded in case of symlink loops
+    for _ in 0..40 {
+        let target = fs::read_link(&current).ok()?;
+        let target = match current.parent() {
+            Some(parent) if target.is_relative() => parent.join(target),
+            _ => target,
+        };
+        if generationprofile(&target).is_some() {
+            return Some(current);
+        }
+        current = target;
+    }
+    None
+}
+
+/// Returns the profile of the generation `path`, such as `profile` for `profile-5-link`.
+fn generationprofile(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.to_str()?;
+    let (profile, number) = name.strip_suffix("-link")?.rsplit_once('-')?;
+    if profile.is_empty() || number.is_empty() || !number.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    Some(path.with_file_name(profile))
+}
+
+/// Identifies `profile`, or the default profile if it is `None`, independent of its current generation.
+pub(crate) fn profileid(profile: Option<&Path>) -> Result<Option<PathBuf>> {
+    let link = match profile {
+        Some(profile) => profile.to_path_buf(),
+        None => match defaultprofile()? {
+            Some(link) => link,
+            None => return Ok(None),
+        },
+    };
+    Ok(Some(profilelink(&link).unwrap_or(link)))
+}
+
+/// Returns the directory of `profile` with all symlinks resolved, or of the default profile if it is `None`.
+/// Returns `None` if there is no default profile, and errors if `profile` doesn't exist.
+pub(crate) fn profiledir(profile: Option<&Path>) -> Result<Option<PathBuf>> {
+    match profile {
+        Some(profile) => Ok(Some(fs::canonicalize(profile)?)),
+        None => Ok(defaultprofile()?.and_then(|link| fs::canonicalize(link).ok())),
+    }
+}
+
+/// Path to the `manifest.json` of `profile`, if it exists.
+/// Profiles managed by `nix-env` have a `manifest.nix` instead.
+fn manifestpath(profile: Option<&Path>) -> Result<Option<PathBuf>> {
+    Ok(profiledir(profile)?
+        .map(|dir| dir.join("manifest.json"))
+        .filter(|path| path.exists()))
 }
 
 /// Returns a list of all packages installed with `nix profile` with their name.
 /// Does not include individual version.
-pub fn getprofilepkgs() -> Result<HashMap<String, ProfilePkg>> {
-    if !Path::new(&format!(
-        "{}/.nix-profile/manifest.json",
-        std::env::var("HOME")?
-    ))
-    .exists()
-    {
+/// Reads `profile`, such as `~/profiles/dev`, or the [default profile](defaultprofile) of the user if it is `None`.
+pub fn getprofilepkgs(profile: Option<&Path>) -> Result<HashMap<String, ProfilePkg>> {
+    let manifest = if let Some(path) = manifestpath(profile)? {
+        path
+    } else {
         return Ok(HashMap::new());
+    };
+    let profileroot: ProfilePkgsRoot = serde_json::from_reader(File::open(manifest)?)?;
+    if profileroot.version > MANIFESTVERSION {
+        return Err(Error::Parse(format!(
+            "Unsupported profile manifest version {}",
+            profileroot.version
+        )));
     }
-    let profileroot: ProfilePkgsRoot = serde_json::from_reader(File::open(&format!(
-        "{}/.nix-profile/manifest.json",
-        std::env::var("HOME")?
-    ))?)?;
+    let elements = match profileroot.elements {
+        ProfileElements::List(elements) => elementnames(elements),
+        ProfileElements::Map(elements) => elements.into_iter().collect(),
+    };
     let mut out = HashMap::new();
-    for pkg in profileroot.elements {
+    for (elementname, pkg) in elements {
         if let (Some(attrpath), Some(originalurl)) = (pkg.attrpath, pkg.originalurl) {
             let attr = if attrpath.starts_with("legacyPackages") {
                 attrpath
                     .split('.')
                     .collect::<Vec<_>>()
                     .get(2..)
-                    .context("Failed to get legacyPackage attribute")?
+                    .ok_or_else(|| {
+                        Error::Parse(String::from("Failed to get legacyPackage attribute"))
+                    })?
                     .join(".")
             } else {
                 format!("{}#{}", originalurl, attrpath)
             };
-            if let Some(first) = pkg.storepaths.get(0) {
-                let ver = first
-                    .get(44..)
-                    .context("Failed to get pkg name from store path")?;
+            if let Some(first) = pkg.storepaths.first() {
+                let storepath = StorePath::parse(first)?;
                 out.insert(
                     attr,
                     ProfilePkg {
-                        name: ver.to_string(),
+                        name: storepath.name().to_string(),
+                        storepath,
                         originalurl,
+                        lockedrev: pkg.url.as_deref().and_then(lockedrev),
+                        url: pkg.url,
+                        elementname,
+                        priority: pkg.priority,
+                        active: pkg.active,
                     },
                 );
             }
@@ -83,57 +287,203 @@ pub fn getprofilepkgs() -> Result<HashMap<String, ProfilePkg>> {
     Ok(out)
 }
 
+/// Names elements of manifests before version 3 similar to how Nix does when upgrading them,
+/// after the last component of their attribute path, their flake or their store path name, made unique with a suffix.
+fn elementnames(elements: Vec<ProfilePkgOut>) -> Vec<(String, ProfilePkgOut)> {
+    let mut used = HashSet::new();
+    elements
+        .into_iter()
+        .map(|pkg| {
+            let storepath = pkg
+                .storepaths
+                .first()
+                .and_then(|p| StorePath::parse(p).ok());
+            let base = pkg
+                .attrpath
+                .as_deref()
+                .and_then(|a| a.rsplit('.').next())
+                .and_then(|a| {
+                    if a == "default" {
+                        // Default packages are named after their flake, such as `bar` for `github:foo/bar`
+                        pkg.originalurl
+                            .as_deref()
+                            .and_then(|u| u.split(['?', '#']).next())
+                            .and_then(|u| u.rsplit(['/', ':']).next())
+                    } else {
+                        Some(a)
+                    }
+                })
+                .or_else(|| storepath.as_ref().map(|p| p.pname()))
+                .unwrap_or("package")
+                .to_string();
+            let mut name = base.to_string();
+            let mut i = 1;
+            while !used.insert(name.to_string()) {
+                name = format!("{}-{}", base, i);
+                i += 1;
+            }
+            (name, pkg)
+        })
+        .collect()
+}
+
 /// Returns a list of all packages installed with `nix profile` with their name and version.
 /// Takes a bit longer than [getprofilepkgs()].
-pub async fn getprofilepkgs_versioned() -> Result<HashMap<String, String>> {
-    if !Path::new(&format!(
-        "{}/.nix-profile/manifest.json",
-        std::env::var("HOME")?
-    ))
-    .exists()
-    {
+///
+/// Packages from nixpkgs have their latest version in nixpkgs.
+/// Packages from other flakes have their installed version,
+/// from the store path name or the date of the flake revision if the name has no version.
+pub async fn getprofilepkgs_versioned(profile: Option<&Path>) -> Result<HashMap<String, String>> {
+    if manifestpath(profile)?.is_none() {
         return Ok(HashMap::new());
     }
-    let profilepkgs = getprofilepkgs()?;
-    let latestpkgs = if Path::new(&format!("{}/nixpkgs.db", &*CACHEDIR)).exists() {
-        format!("{}/nixpkgs.db", &*CACHEDIR)
+    let profilepkgs = getprofilepkgs(profile)?;
+    let latestpkgs = if Path::new(&format!("{}/nixpkgs.db", cachedir())).exists() {
+        format!("{}/nixpkgs.db", cachedir())
     } else {
         // Change to something else if overridden
-        nixpkgslatest().await?
+        nixpkgslatest().await?.path
     };
+    let db = PackageDb::open(latestpkgs).await?;
+    let mut versions = db.versions(&profilepkgs.keys().collect::<Vec<_>>()).await?;
+    for (attr, pkg) in profilepkgs.iter().filter(|(attr, _)| isflakepkg(attr)) {
+        let version = match pkg.storepath.version() {
+            Some(v) => Some(v.to_string()),
+            None => match &pkg.url {
+                Some(url) => flakemetadata(url, false)?
+                    .and_then(|m| m.lastmodified)
+                    .map(|t| format!("unstable-{}", date(t))),
+                None => None,
+            },
+        };
+        if let Some(version) = version {
+            versions.insert(attr.to_string(), version);
+        }
+    }
+    Ok(versions)
+}
+
+/// Revision of a package installed with `nix profile` from a flake other than nixpkgs,
+/// compared to the latest revision of the flake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlakeRevision {
+    /// Revision the package was installed from.
+    pub lockedrev: Option<String>,
+    /// Latest revision of the flake, or `None` if it couldn't be fetched.
+    pub latestrev: Option<String>,
+    /// Whether the flake has a newer revision than the installed one.
+    pub newer: bool,
+}
+
+/// Returns the revisions of all packages installed with `nix profile` from flakes other than nixpkgs.
+/// Fetches the latest revision of each flake, so this needs network access.
+pub fn getflakerevisions(profile: Option<&Path>) -> Result<HashMap<String, FlakeRevision>> {
+    let mut latest: HashMap<String, Option<FlakeMetadataOut>> = HashMap::new();
     let mut out = HashMap::new();
-    let pool = SqlitePool::connect(&format!("sqlite://{}", latestpkgs)).await?;
-    for (pkg, _v) in profilepkgs {
-        let versions: Vec<(String,)> = sqlx::query_as(
-            r#"
-            SELECT version FROM pkgs WHERE attribute = $1
-            "#,
-        )
-        .bind(&pkg)
-        .fetch_all(&pool)
-        .await?;
-        if !versions.is_empty() {
-            out.insert(pkg, versions.get(0).unwrap().0.to_string());
+    for (attr, pkg) in getprofilepkgs(profile)? {
+        if !isflakepkg(&attr) {
+            continue;
+        }
+        if !latest.contains_key(&pkg.originalurl) {
+            let metadata = flakemetadata(&pkg.originalurl, true)?;
+            latest.insert(pkg.originalurl.to_string(), metadata);
         }
+        let upstream = latest.get(&pkg.originalurl).and_then(|m| m.as_ref());
+        let latestrev = upstream.and_then(|m| m.rev()).map(|rev| rev.to_string());
+        let newer = match (&pkg.lockedrev, &latestrev) {
+            (Some(locked), Some(latest)) if locked != latest => {
+                // A different revision is only newer if it was committed later
+                let lockedtime = match &pkg.url {
+                    Some(url) => flakemetadata(url, false)?.and_then(|m| m.lastmodified),
+                    None => None,
+                };
+                match (lockedtime, upstream.and_then(|m| m.lastmodified)) {
+                    (Some(lockedtime), Some(latesttime)) => latesttime > lockedtime,
+                    _ => true,
+                }
+            }
+            _ => false,
+        };
+        out.insert(
+            attr,
+            FlakeRevision {
+                lockedrev: pkg.lockedrev,
+                latestrev,
+                newer,
+            },
+        );
     }
     Ok(out)
 }
 
-/// Downloads a list of available package versions `packages.db`
-/// and returns the path to the file.
-pub async fn nixpkgslatest() -> Result<String> {
-    // If cache directory doesn't exist, create it
-    if !std::path::Path::new(&*CACHEDIR).exists() {
-        std::fs::create_dir_all(&*CACHEDIR)?;
+/// Whether the profile package with the key `attr` is from a flake other than nixpkgs.
+pub(crate) fn isflakepkg(attr: &str) -> bool {
+    attr.contains('#')
+}
+
+/// Returns the output of `nix flake metadata` for `flakeref`, or `None` if it can't be fetched.
+/// With `refresh`, the latest version of unlocked references is fetched instead of using the cached one.
+pub(super) fn flakemetadata(flakeref: &str, refresh: bool) -> Result<Option<FlakeMetadataOut>> {
+    let mut cmd = Command::new("nix");
+    cmd.arg("flake").arg("metadata").arg("--json");
+    if refresh {
+        cmd.arg("--refresh");
+    }
+    let output = utils::output(cmd.arg(flakeref))?;
+    if !output.status.success() {
+        info!(
+            "Failed to get metadata of {}: {}",
+            flakeref,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        return Ok(None);
+    }
+    Ok(serde_json::from_slice(&output.stdout).ok())
+}
+
+/// Revision in a locked flake reference, such as `github:owner/repo/<rev>` or `git+https://host/repo?rev=<rev>`.
+fn lockedrev(url: &str) -> Option<String> {
+    let (base, query) = url.split_once('?').unwrap_or((url, ""));
+    if let Some(rev) = query
+        .split('&')
+        .find_map(|param| param.strip_prefix("rev="))
+    {
+        return Some(rev.to_string());
+    }
+    let (scheme, path) = base.split_once(':')?;
+    if ["github", "gitlab", "sourcehut"].contains(&scheme) {
+        path.split('/')
+            .nth(2)
+            .filter(|rev| rev.len() == 40 && rev.chars().all(|c| c.is_ascii_hexdigit()))
+            .map(|rev| rev.to_string())
+    } else {
+        None
     }
+}
+
+/// Formats a Unix timestamp as a `YYYY-MM-DD` date.
+fn date(timestamp: u64) -> String {
+    // Civil from days algorithm by Howard Hinnant
+    let days = (timestamp / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
 
+/// Downloads a list of available package versions `packages.db`
+/// and returns the path to the file.
+pub async fn nixpkgslatest() -> Result<CachedFile> {
     let mut nixpkgsver = None;
-    let mut pinned = false;
-    let regout = Command::new("nix").arg("registry").arg("list").output()?;
+    let mut pinned = None;
+    let regout = utils::output(Command::new("nix").arg("registry").arg("list"))?;
     let reg = String::from_utf8(regout.stdout)?.replace("   ", " ");
 
-    let mut latestnixpkgsver = String::new();
-
     for l in reg.split('\n') {
         let parts = l.split(' ').collect::<Vec<_>>();
         if let Some(x) = parts.get(1) {
@@ -148,8 +498,7 @@ pub async fn nixpkgslatest() -> Result<String> {
                                 rev
                             );
                             nixpkgsver = Some(rev.to_string());
-                            latestnixpkgsver = rev.to_string();
-                            pinned = true;
+                            pinned = Some(rev.to_string());
                         }
                     }
                     break;
@@ -158,152 +507,224 @@ pub async fn nixpkgslatest() -> Result<String> {
         }
     }
 
-    if !pinned {
-        let verurl = if let Some(v) = &nixpkgsver {
-            format!(
-                "https://raw.githubusercontent.com/snowflakelinux/nix-data-db/main/{}/nixpkgs.ver",
-                v
-            )
-        } else {
-            String::from("https://raw.githubusercontent.com/snowflakelinux/nix-data-db/main/nixpkgs-unstable/nixpkgs.ver")
-        };
-        debug!("Checking nixpkgs version");
-        let resp = reqwest::get(&verurl).await;
-        let resp = if let Ok(r) = resp {
-            r
-        } else {
-            // Internet connection failed
-            // Check if we can use the old database
-            let dbpath = format!("{}/nixpkgs.db", &*CACHEDIR);
-            if Path::new(&dbpath).exists() {
-                info!("Using old database");
-                return Ok(dbpath);
-            } else {
-                return Err(anyhow!("Could not find latest nixpkgs version"));
-            }
-        };
-        latestnixpkgsver = if resp.status().is_success() {
-            resp.text().await?
-        } else {
-            return Err(anyhow!("Could not find latest nixpkgs version"));
-        };
-        debug!("Latest nixpkgs version: {}", latestnixpkgsver);
-    }
-
-    // Check if latest version is already downloaded
-    if let Ok(prevver) = fs::read_to_string(&format!("{}/nixpkgs.ver", &*CACHEDIR)) {
-        if prevver == latestnixpkgsver && Path::new(&format!("{}/nixpkgs.db", &*CACHEDIR)).exists()
-        {
-            debug!("No new version of nixpkgs found");
-            return Ok(format!("{}/nixpkgs.db", &*CACHEDIR));
+    let mirrors = mirror::mirrors()?;
+    let release = if let Some(rev) = pinned {
+        Release {
+            sources: vec![Source::Download {
+                url: format!("{}/nixos-unstable/{}.json.br", mirrors.versiondata, rev),
+                format: Format::Versions,
+                verify: true,
+            }],
+            version: Version::Known(rev),
         }
-    }
-
-    let url = if pinned {
-        format!(
-            "https://raw.githubusercontent.com/snowflakelinux/nixpkgs-version-data/main/nixos-unstable/{}.json.br",
-            latestnixpkgsver
-        )
-    } else if let Some(v) = &nixpkgsver {
-        format!(
-            "https://raw.githubusercontent.com/snowflakelinux/nix-data-db/main/{}/nixpkgs_versions.db.br",
-            v
-        )
     } else {
-        String::from("https://raw.githubusercontent.com/snowflakelinux/nix-data-db/main/nixpkgs-unstable/nixpkgs_versions.db.br")
-    };
-    debug!("Downloading nix-data database");
-    let client = reqwest::Client::builder().brotli(true).build()?;
-    let resp = client.get(url).send().await?;
-    if resp.status().is_success() {
-        debug!("Writing nix-data database");
-        // let mut out = File::create(&format!("{}/nixpkgs.db", &*CACHEDIR))?;
-        {
-            let bytes = resp.bytes().await?;
-            let mut br = brotli::Decompressor::new(bytes.as_ref(), 4096);
-            let mut pkgsout = Vec::new();
-            br.read_to_end(&mut pkgsout)?;
-            if pinned {
-                let pkgsjson: HashMap<String, String> = serde_json::from_slice(&pkgsout)?;
-                let dbfile = format!("{}/nixpkgs.db", &*CACHEDIR);
-                nixos::createdb(&dbfile, &pkgsjson).await?;
-            } else {
-                let mut out = File::create(&format!("{}/nixpkgs.db", &*CACHEDIR))?;
-                if let Err(e) = out.write_all(&pkgsout) {
-                    error!("{}", e);
-                    return Err(anyhow!("Failed write to nixpkgs.br"));
-                }
-            }
+        let channel = nixpkgsver.as_deref().unwrap_or("nixpkgs-unstable");
+        Release {
+            version: Version::Url(format!("{}/{}/nixpkgs.ver", mirrors.db, channel)),
+            sources: vec![Source::Download {
+                url: format!("{}/{}/nixpkgs_versions.db.br", mirrors.db, channel),
+                format: Format::Raw,
+                verify: true,
+            }],
         }
-        debug!("Writing nix-data version");
-        // Write version downloaded to file
-        File::create(format!("{}/nixpkgs.ver", &*CACHEDIR))?
-            .write_all(latestnixpkgsver.as_bytes())?;
-    } else {
-        return Err(anyhow!("Failed to download latest nixpkgs.db.br"));
+    };
+    CacheEntry {
+        name: "nixpkgs",
+        ext: "db",
+        releases: vec![release],
     }
-    Ok(format!("{}/nixpkgs.db", &*CACHEDIR))
+    .get()
+    .await
 }
 
-pub async fn unavailablepkgs() -> Result<HashMap<String, String>> {
-    let nixpath = Command::new("nix")
-        .arg("eval")
-        .arg("nixpkgs#path")
-        .output()?
-        .stdout;
+/// Returns packages in `profile`, or the [default profile](defaultprofile) if it is `None`,
+/// that are missing, broken or insecure in the latest nixpkgs, with the reason.
+/// Packages from other flakes are not checked.
+pub async fn unavailablepkgs(profile: Option<&Path>) -> Result<HashMap<String, String>> {
+    let nixpath = utils::output(Command::new("nix").arg("eval").arg("nixpkgs#path"))?.stdout;
     let nixpath = String::from_utf8(nixpath)?;
     let nixpath = nixpath.trim();
 
-    let aliases = Command::new("nix-instantiate")
+    let aliases = utils::output(Command::new("nix-instantiate")
         .arg("--eval")
         .arg("-E")
-        .arg(&format!("with import {} {{}}; builtins.attrNames ((self: super: lib.optionalAttrs config.allowAliases (import {}/pkgs/top-level/aliases.nix lib self super)) {{}} {{}})", nixpath, nixpath))
-        .arg("--json")
-        .output()?;
+        .arg(format!("with import {} {{}}; builtins.attrNames ((self: super: lib.optionalAttrs config.allowAliases (import {}/pkgs/top-level/aliases.nix lib self super)) {{}} {{}})", nixpath, nixpath))
+        .arg("--json"))?;
     let aliasstr = String::from_utf8(aliases.stdout)?;
     let aliasesout: HashSet<String> = serde_json::from_str(&aliasstr)?;
 
-    let flakespkgs = getprofilepkgs()?;
+    // Packages from flakes other than nixpkgs can't be found in nixpkgs
+    let flakespkgs = getprofilepkgs(profile)?
+        .into_keys()
+        .filter(|pkg| !isflakepkg(pkg))
+        .collect::<Vec<_>>();
     let mut unavailable = HashMap::new();
-    for pkg in flakespkgs.keys() {
-        if aliasesout.contains(pkg) && Command::new("nix-instantiate")
+    for pkg in &flakespkgs {
+        if aliasesout.contains(pkg) && utils::output(Command::new("nix-instantiate")
                 .arg("--eval")
                 .arg("-E")
-                .arg(&format!("with import {} {{}}; builtins.tryEval ((self: super: lib.optionalAttrs config.allowAliases (import {}/pkgs/top-level/aliases.nix lib self super)) {{}} {{}}).{}", nixpath, nixpath, pkg))
-                .output()?.status.success() {
-            let out = Command::new("nix-instantiate")
+                .arg(format!("with import {} {{}}; builtins.tryEval ((self: super: lib.optionalAttrs config.allowAliases (import {}/pkgs/top-level/aliases.nix lib self super)) {{}} {{}}).{}", nixpath, nixpath, pkg)))?.status.success() {
+            let out = utils::output(Command::new("nix-instantiate")
                 .arg("--eval")
                 .arg("-E")
-                .arg(&format!("with import {} {{}}; ((self: super: lib.optionalAttrs config.allowAliases (import {}/pkgs/top-level/aliases.nix lib self super)) {{}} {{}}).{}", nixpath, nixpath, pkg))
-                .output()?;
+                .arg(format!("with import {} {{}}; ((self: super: lib.optionalAttrs config.allowAliases (import {}/pkgs/top-level/aliases.nix lib self super)) {{}} {{}}).{}", nixpath, nixpath, pkg)))?;
             let err = String::from_utf8(out.stderr)?;
             let err = err.strip_prefix("error: ").unwrap_or(&err).trim();
             unavailable.insert(pkg.to_string(), err.to_string());
         }
     }
 
-    let nixospkgs = nixospkgs().await?;
-    let pool = SqlitePool::connect(&format!("sqlite://{}", nixospkgs)).await?;
-
-    for pkg in flakespkgs.keys() {
-        let (x, broken, insecure): (String, u8, u8) =
-            sqlx::query_as("SELECT attribute,broken,insecure FROM meta WHERE attribute = $1")
-                .bind(pkg)
-                .fetch_one(&pool)
-                .await?;
-        if &x != pkg {
-            unavailable.insert(
-                pkg.to_string(),
-                String::from("Package not found in newer version of nixpkgs"),
-            );
-        } else if broken == 1 {
-            unavailable.insert(pkg.to_string(), String::from("Package is marked as broken"));
-        } else if insecure == 1 {
-            unavailable.insert(
-                pkg.to_string(),
-                String::from("Package is marked as insecure"),
-            );
+    nixos::unavailablemeta(&flakespkgs, &mut unavailable).await?;
+    Ok(unavailable)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REV: &str = "0123456789abcdef0123456789abcdef01234567";
+    const HELLO: &str = "/nix/store/0c8b5csxrk5ngn8jqzf9cmd3x2iky4h9-hello-2.12.1";
+    const TOOL: &str = "/nix/store/1c8b5csxrk5ngn8jqzf9cmd3x2iky4h9-tool-unstable";
+
+    /// Reads the packages of a profile with `manifest` as its `manifest.json`.
+    fn profilepkgs(manifest: &str) -> Result<HashMap<String, ProfilePkg>> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("manifest.json"), manifest)?;
+        getprofilepkgs(Some(dir.path()))
+    }
+
+    fn elements(elements: &str) -> Vec<String> {
+        elementnames(serde_json::from_str(elements).unwrap())
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    #[test]
+    fn readsmanifestv1() {
+        let pkgs = profilepkgs(&format!(
+            r#"{{"version": 1, "elements": [
+                {{"attrPath": "legacyPackages.x86_64-linux.hello", "originalUri": "flake:nixpkgs",
+                  "uri": "github:NixOS/nixpkgs/{rev}", "storePaths": ["{hello}"], "active": false}}
+            ]}}"#,
+            rev = REV,
+            hello = HELLO
+        ))
+        .unwrap();
+        assert_eq!(pkgs.len(), 1);
+        let hello = &pkgs["hello"];
+        assert_eq!(hello.name, "hello-2.12.1");
+        assert_eq!(hello.originalurl, "flake:nixpkgs");
+        assert_eq!(hello.lockedrev.as_deref(), Some(REV));
+        assert_eq!(hello.elementname, "hello");
+        assert_eq!(hello.priority, 5);
+        assert!(!hello.active);
+    }
+
+    #[test]
+    fn readsmanifestv2() {
+        let pkgs = profilepkgs(&format!(
+            r#"{{"version": 2, "elements": [
+                {{"attrPath": "legacyPackages.x86_64-linux.hello", "originalUrl": "flake:nixpkgs",
+                  "url": "github:NixOS/nixpkgs/{rev}", "storePaths": ["{hello}"], "priority": 3}},
+                {{"attrPath": "packages.x86_64-linux.default", "originalUrl": "github:owner/tool",
+                  "url": "github:owner/tool/{rev}", "storePaths": ["{tool}"]}},
+                {{"storePaths": ["{hello}"]}}
+            ]}}"#,
+            rev = REV,
+            hello = HELLO,
+            tool = TOOL
+        ))
+        .unwrap();
+        // Elements without an attribute path are not from a flake
+        assert_eq!(pkgs.len(), 2);
+        assert_eq!(pkgs["hello"].elementname, "hello");
+        assert_eq!(pkgs["hello"].priority, 3);
+        assert!(pkgs["hello"].active);
+        let tool = &pkgs["github:owner/tool#packages.x86_64-linux.default"];
+        assert_eq!(tool.elementname, "tool");
+        assert_eq!(tool.storepath.version(), None);
+        assert!(isflakepkg(
+            "github:owner/tool#packages.x86_64-linux.default"
+        ));
+    }
+
+    #[test]
+    fn readsmanifestv3() {
+        let pkgs = profilepkgs(&format!(
+            r#"{{"version": 3, "elements": {{
+                "my-hello": {{"attrPath": "legacyPackages.x86_64-linux.hello", "originalUrl": "flake:nixpkgs",
+                  "url": "github:NixOS/nixpkgs/{rev}", "storePaths": ["{hello}"], "priority": 5}}
+            }}}}"#,
+            rev = REV,
+            hello = HELLO
+        ))
+        .unwrap();
+        assert_eq!(pkgs.len(), 1);
+        assert_eq!(pkgs["hello"].elementname, "my-hello");
+    }
+
+    #[test]
+    fn rejectsnewermanifests() {
+        assert!(matches!(
+            profilepkgs(r#"{"version": 4, "elements": {}}"#),
+            Err(Error::Parse(_))
+        ));
+        assert!(profilepkgs("{}").is_err());
+    }
+
+    #[test]
+    fn nameselements() {
+        assert_eq!(
+            elements(&format!(
+                r#"[
+                    {{"attrPath": "legacyPackages.x86_64-linux.hello", "storePaths": ["{hello}"]}},
+                    {{"attrPath": "legacyPackages.aarch64-linux.hello", "storePaths": ["{hello}"]}},
+                    {{"attrPath": "packages.x86_64-linux.default", "originalUrl": "github:owner/tool?dir=sub#x", "storePaths": []}},
+                    {{"attrPath": "packages.x86_64-linux.default", "originalUrl": "path:/home/user/tool", "storePaths": []}},
+                    {{"storePaths": ["{hello}"]}},
+                    {{"storePaths": []}}
+                ]"#,
+                hello = HELLO
+            )),
+            ["hello", "hello-1", "tool", "tool-1", "hello-2", "package"]
+        );
+    }
+
+    #[test]
+    fn readslockedrevs() {
+        for (url, rev) in [
+            (format!("github:NixOS/nixpkgs/{}", REV), Some(REV)),
+            (format!("gitlab:owner/repo/{}", REV), Some(REV)),
+            (format!("sourcehut:~owner/repo/{}", REV), Some(REV)),
+            (format!("github:NixOS/nixpkgs/{}?dir=lib", REV), Some(REV)),
+            (
+                format!("git+https://example.com/repo?ref=main&rev={}", REV),
+                Some(REV),
+            ),
+            (
+                format!("path:/nix/store/x?lastModified=1&rev={}", REV),
+                Some(REV),
+            ),
+            // Branches and short revisions are not locked
+            (String::from("github:NixOS/nixpkgs/nixos-unstable"), None),
+            (String::from("github:NixOS/nixpkgs/0123456"), None),
+            (String::from("github:NixOS/nixpkgs"), None),
+            (format!("git+https://example.com/repo/{}", REV), None),
+            (String::from("path:/home/user/flake"), None),
+        ] {
+            assert_eq!(lockedrev(&url).as_deref(), rev, "{}", url);
         }
     }
-    Ok(unavailable)
+
+    #[test]
+    fn formatsdates() {
+        assert_eq!(date(0), "1970-01-01");
+        assert_eq!(date(86399), "1970-01-01");
+        assert_eq!(date(951782400), "2000-02-29");
+        assert_eq!(date(951868800), "2000-03-01");
+        assert_eq!(date(1700000000), "2023-11-14");
+        assert_eq!(date(1704067199), "2023-12-31");
+        assert_eq!(date(4107542400), "2100-03-01");
+    }
 }
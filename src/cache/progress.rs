@@ -0,0 +1,69 @@
+use tokio::sync::watch;
+
+/// Stage of a cache refresh.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Waiting for another process that is refreshing the same entry.
+    Waiting,
+    /// Checking whether a newer version is available.
+    CheckingVersion,
+    /// Downloading from a mirror. Downloads are decompressed as they arrive.
+    /// Progress is in compressed bytes.
+    Downloading,
+    /// Finished decompressing a download. Progress is in decompressed bytes.
+    Decompressing,
+    /// Importing packages into an SQLite database. Progress is in packages.
+    Importing,
+    /// The refresh has finished, successfully or not.
+    Done,
+}
+
+/// Progress of a cache refresh.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Progress {
+    /// Name of the cache entry being refreshed, such as `nixospkgs` or `nixosoptions`.
+    pub entry: String,
+    pub phase: Phase,
+    /// Amount of work done in the current phase.
+    pub done: u64,
+    /// Total amount of work in the current phase, if known.
+    pub total: Option<u64>,
+}
+
+lazy_static::lazy_static! {
+    static ref PROGRESS: watch::Sender<Progress> = watch::channel(Progress {
+        entry: String::new(),
+        phase: Phase::Done,
+        done: 0,
+        total: None,
+    }).0;
+}
+
+/// Returns a receiver that is updated with the progress of every cache refresh,
+/// such as [nixospkgs()](crate::cache::nixos::nixospkgs) or [nixpkgslatest()](crate::cache::profile::nixpkgslatest).
+///
+/// # Example
+/// ```no_run
+/// # async fn example() {
+/// let mut progress = nix_data::cache::progress::subscribe();
+/// tokio::spawn(async move {
+///     while progress.changed().await.is_ok() {
+///         let p = progress.borrow().clone();
+///         println!("{}: {:?} {}/{:?}", p.entry, p.phase, p.done, p.total);
+///     }
+/// });
+/// let _ = nix_data::cache::nixos::nixospkgs().await;
+/// # }
+/// ```
+pub fn subscribe() -> watch::Receiver<Progress> {
+    PROGRESS.subscribe()
+}
+
+pub(crate) fn report(entry: &str, phase: Phase, done: u64, total: Option<u64>) {
+    PROGRESS.send_replace(Progress {
+        entry: entry.to_string(),
+        phase,
+        done,
+        total,
+    });
+}
@@ -0,0 +1,221 @@
+use crate::{config::configfile::getconfigordefault, Result};
+use log::debug;
+use minisign_verify::{PublicKey, Signature, StreamVerifier};
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+use super::mirror;
+
+/// Error returned when a downloaded file fails verification.
+/// The file is discarded and the previously cached data is kept.
+#[derive(Debug)]
+pub enum IntegrityError {
+    /// No checksum was found for the download, but `requirechecksum` is set in the config.
+    MissingChecksum(String),
+    /// The SHA-256 checksum of the download doesn't match the one in its `.sha256` file.
+    ChecksumMismatch {
+        url: String,
+        expected: String,
+        actual: String,
+    },
+    /// No `.minisig` signature was found, but a `publickey` is set in the config.
+    MissingSignature(String),
+    /// The signature is malformed or doesn't match the download.
+    InvalidSignature { url: String, reason: String },
+    /// The `publickey` in the config couldn't be decoded.
+    InvalidPublicKey(String),
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IntegrityError::MissingChecksum(url) => write!(f, "No checksum found for {}", url),
+            IntegrityError::ChecksumMismatch {
+                url,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Checksum mismatch for {}: expected {}, got {}",
+                url, expected, actual
+            ),
+            IntegrityError::MissingSignature(url) => write!(f, "No signature found for {}", url),
+            IntegrityError::InvalidSignature { url, reason } => {
+                write!(f, "Invalid signature for {}: {}", url, reason)
+            }
+            IntegrityError::InvalidPublicKey(reason) => {
+                write!(f, "Invalid public key: {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for IntegrityError {}
+
+/// Expected checksum and signature of a download.
+pub(crate) struct Integrity {
+    url: String,
+    sha256: Option<String>,
+    signature: Option<(PublicKey, Signature)>,
+}
+
+/// Incremental verifier for a single download, created with [Integrity::verifier()].
+pub(crate) struct Verifier<'a> {
+    integrity: &'a Integrity,
+    sha256: Sha256,
+    signature: Option<StreamVerifier<'a>>,
+}
+
+impl Integrity {
+    /// Fetches the checksum of `url` from the checksum file `checksumurl` and its signature from `{url}.minisig`.
+    /// Returns `None` if the mirror can't be reached.
+    pub async fn fetch(url: &str, checksumurl: &str) -> Result<Option<Integrity>> {
+        let config = getconfigordefault()?;
+
+        let sha256 = match mirror::tryget(checksumurl).await? {
+            Some(resp) if resp.success => parsechecksum(&resp.text().await?, url),
+            Some(_) => None,
+            None => return Ok(None),
+        };
+        if sha256.is_none() && config.requirechecksum == Some(true) {
+            return Err(IntegrityError::MissingChecksum(url.to_string()).into());
+        }
+
+        let signature = if let Some(key) = &config.publickey {
+            let key = PublicKey::from_base64(key.trim())
+                .or_else(|_| PublicKey::decode(key))
+                .map_err(|e| IntegrityError::InvalidPublicKey(e.to_string()))?;
+            let resp = match mirror::tryget(&format!("{}.minisig", url)).await? {
+                Some(resp) if resp.success => resp,
+                Some(_) => return Err(IntegrityError::MissingSignature(url.to_string()).into()),
+                None => return Ok(None),
+            };
+            let sig = Signature::decode(&resp.text().await?).map_err(|e| {
+                IntegrityError::InvalidSignature {
+                    url: url.to_string(),
+                    reason: e.to_string(),
+                }
+            })?;
+            Some((key, sig))
+        } else {
+            None
+        };
+
+        Ok(Some(Integrity {
+            url: url.to_string(),
+            sha256,
+            signature,
+        }))
+    }
+
+    pub fn verifier(&self) -> Result<Verifier<'_>, IntegrityError> {
+        let signature = match &self.signature {
+            Some((key, sig)) => {
+                Some(
+                    key.verify_stream(sig)
+                        .map_err(|e| IntegrityError::InvalidSignature {
+                            url: self.url.to_string(),
+                            reason: e.to_string(),
+                        })?,
+                )
+            }
+            None => None,
+        };
+        Ok(Verifier {
+            integrity: self,
+            sha256: Sha256::new(),
+            signature,
+        })
+    }
+}
+
+impl Verifier<'_> {
+    pub fn update(&mut self, buf: &[u8]) {
+        self.sha256.update(buf);
+        if let Some(sig) = &mut self.signature {
+            sig.update(buf);
+        }
+    }
+
+    pub fn finish(mut self) -> Result<(), IntegrityError> {
+        let url = &self.integrity.url;
+        if let Some(expected) = &self.integrity.sha256 {
+            let actual = self
+                .sha256
+                .finalize()
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>();
+            if &actual != expected {
+                return Err(IntegrityError::ChecksumMismatch {
+                    url: url.to_string(),
+                    expected: expected.to_string(),
+                    actual,
+                });
+            }
+            debug!("Checksum of {} verified", url);
+        }
+        if let Some(sig) = &mut self.signature {
+            sig.finalize()
+                .map_err(|e| IntegrityError::InvalidSignature {
+                    url: url.to_string(),
+                    reason: e.to_string(),
+                })?;
+            debug!("Signature of {} verified", url);
+        }
+        Ok(())
+    }
+}
+
+/// Reads the checksum of the file at `url` from a checksum file,
+/// either a bare hash or `sha256sum` output listing the file by name.
+/// Returns `None` if the file isn't listed.
+fn parsechecksum(manifest: &str, url: &str) -> Option<String> {
+    let filename = url.rsplit('/').next().unwrap_or(url);
+    let lines = manifest
+        .lines()
+        .map(|l| l.split_whitespace().collect::<Vec<_>>())
+        .filter(|l| !l.is_empty())
+        .collect::<Vec<_>>();
+    let hash = match &lines[..] {
+        [line] if line.len() == 1 => line[0],
+        _ => lines
+            .iter()
+            .find(|l| l.get(1).map(|f| f.trim_start_matches('*')) == Some(filename))?[0],
+    };
+    Some(hash.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    const OTHER: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    #[test]
+    fn parseschecksums() {
+        let url = "https://example.com/nixpkgs/nixpkgs.db.br";
+        assert_eq!(parsechecksum(HASH, url).as_deref(), Some(HASH));
+        assert_eq!(
+            parsechecksum(&format!("\n{}\n", HASH.to_uppercase()), url).as_deref(),
+            Some(HASH)
+        );
+        let manifest = format!("{}  nixpkgs.ver\n{} *nixpkgs.db.br\n", OTHER, HASH);
+        assert_eq!(parsechecksum(&manifest, url).as_deref(), Some(HASH));
+        assert_eq!(
+            parsechecksum(&format!("{}  nixpkgs.db.br\n", HASH), url).as_deref(),
+            Some(HASH)
+        );
+        // Not listed
+        assert_eq!(
+            parsechecksum(&format!("{}  nixpkgs.ver\n", OTHER), url),
+            None
+        );
+        assert_eq!(
+            parsechecksum(&format!("{}  nixos.db.br\n", OTHER), url),
+            None
+        );
+        assert_eq!(parsechecksum("", url), None);
+    }
+}
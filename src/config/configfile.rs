@@ -1,11 +1,13 @@
-
-use crate::{CONFIG, SYSCONFIG, CONFIGDIR};
-use anyhow::{Result, anyhow};
+use crate::{
+    context::{configdir, configfile},
+    Error, Result, SYSCONFIG,
+};
 use serde::{Deserialize, Serialize};
 use std::{
     fs::{self, File},
-    io::{Write, BufReader},
+    io::{BufReader, Write},
     path::Path,
+    time::Duration,
 };
 
 /// Struct containing locations of system configuration files and some user configuration.
@@ -21,8 +23,64 @@ pub struct NixDataConfig {
     /// Specifies how many NixOS generations to keep. If set to 0, all generations will be kept.
     /// If not set, the default is 5.
     pub generations: Option<u32>,
+    /// Base URL of the `nix-data-db` mirror used for package databases and Home Manager options.
+    /// All mirror options also accept `file://` URLs pointing to a local copy of the mirror.
+    /// If not set, the default is `https://raw.githubusercontent.com/snowflakelinux/nix-data-db/main`.
+    pub dbmirror: Option<String>,
+    /// Base URL of the `nixpkgs-version-data` mirror used for package versions of specific nixpkgs revisions.
+    /// If not set, the default is `https://raw.githubusercontent.com/snowflakelinux/nixpkgs-version-data/main`.
+    pub versionmirror: Option<String>,
+    /// Base URL of the NixOS channels mirror. If not set, the default is `https://channels.nixos.org`.
+    /// Each channel, such as `nixos-23.05`, needs its `git-revision` and `options.json.br` files.
+    pub channelmirror: Option<String>,
+    /// Base URL of the NixOS releases mirror. If not set, the default is `https://releases.nixos.org`.
+    pub releasemirror: Option<String>,
+    /// Whether downloaded databases must have a checksum in the `.sha256` manifest next to their `.ver` file on the mirror,
+    /// such as `nixos-23.05/nixpkgs.sha256` for `nixos-23.05/nixpkgs.db.br`.
+    /// Version data of specific nixpkgs revisions has no `.ver` file and uses `{file}.sha256` instead.
+    /// If not set, checksums are only verified when the mirror provides them.
+    ///
+    /// Only downloads from the `dbmirror` and `versionmirror` are verified.
+    /// The NixOS channels and releases (`channelmirror` and `releasemirror`), used for NixOS options
+    /// and the packages of the legacy channel, publish no checksums and are never verified, not even through a configured mirror.
+    pub requirechecksum: Option<bool>,
+    /// Minisign public key used to verify the `.minisig` signature of downloaded databases.
+    /// If set, downloads from the `dbmirror` and `versionmirror` without a valid signature are rejected.
+    /// Like checksums, downloads from the `channelmirror` and `releasemirror` are not verified.
+    pub publickey: Option<String>,
+    /// When cached package and option data should be refreshed.
+    /// If not set, the default is [AlwaysRefresh](CachePolicy::AlwaysRefresh).
+    pub cachepolicy: Option<CachePolicy>,
+}
+
+/// When functions in the [cache](crate::cache) module check for and download newer data.
+///
+/// In the config file, this is written as `"alwaysrefresh"`, `"offlineonly"`
+/// or `{ "refreshifolderthan": 3600 }` with the age in seconds.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CachePolicy {
+    /// Check for a newer version on every call, using the cached data only if the mirror can't be reached.
+    #[default]
+    AlwaysRefresh,
+    /// Only check for a newer version if the cached data wasn't checked within the given duration.
+    RefreshIfOlderThan(#[serde(with = "seconds")] Duration),
+    /// Never access the network and only use cached data.
+    OfflineOnly,
 }
 
+mod seconds {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_secs())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        Ok(Duration::from_secs(u64::deserialize(deserializer)?))
+    }
+}
 
 /// Type of package management used by the user.
 /// - [Profile](UserPkgType::Profile) refers to the `nix profile` command.
@@ -37,29 +95,40 @@ pub enum UserPkgType {
 /// If the config file doesn't exist in both the user (`~/.config/nix-data`) and system (`/etc/nix-data`) config directories,
 /// this function will return an error.
 pub fn getconfig() -> Result<NixDataConfig> {
-    // Check if user config exists
-    if Path::new(&*CONFIG).exists() {
-        // Read user config
-        let config: NixDataConfig = serde_json::from_reader(BufReader::new(File::open(&*CONFIG)?))?;
-        Ok(config)
+    readconfig()?.ok_or_else(|| Error::Config(String::from("No config file found")))
+}
+
+/// Reads the config file, returning the default config if there is none.
+/// A config file that can't be parsed is still an error, so its settings aren't silently ignored.
+pub(crate) fn getconfigordefault() -> Result<NixDataConfig> {
+    Ok(readconfig()?.unwrap_or_default())
+}
+
+/// Reads the user config, or the system config if there is no user config.
+fn readconfig() -> Result<Option<NixDataConfig>> {
+    let userconfig = configfile();
+    let path = if Path::new(&userconfig).exists() {
+        userconfig
     } else if Path::new(SYSCONFIG).exists() {
-        // Read system config
-        let config: NixDataConfig = serde_json::from_reader(BufReader::new(File::open(SYSCONFIG)?))?;
-        Ok(config)
-    }  else {
-        Err(anyhow!("No config file found"))
-    }
+        SYSCONFIG.to_string()
+    } else {
+        return Ok(None);
+    };
+    let config = serde_json::from_reader(BufReader::new(File::open(&path)?))
+        .map_err(|e| Error::Config(format!("Invalid config file {}: {}", path, e)))?;
+    Ok(Some(config))
 }
 
 /// Writes the config struct to the config file in the user config directory (`~/.config/nix-data`).
 pub fn setuserconfig(config: NixDataConfig) -> Result<()> {
     // Check if config directory exists
-    if !Path::new(&*CONFIGDIR).exists() {
-        fs::create_dir_all(&*CONFIGDIR)?;
+    let dir = configdir();
+    if !Path::new(&dir).exists() {
+        fs::create_dir_all(&dir)?;
     }
 
     // Write user config
-    let mut file = File::create(&*CONFIG)?;
+    let mut file = File::create(configfile())?;
     file.write_all(serde_json::to_string_pretty(&config)?.as_bytes())?;
     Ok(())
 }
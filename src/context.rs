@@ -0,0 +1,229 @@
+use crate::{Error, Result};
+use log::warn;
+use std::{
+    env, fs,
+    os::unix::fs::{DirBuilderExt, MetadataExt},
+    path::{Path, PathBuf},
+    process,
+    sync::RwLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Directories used by this crate.
+///
+/// By default, they follow the XDG base directory specification:
+/// the cache is stored in `$XDG_CACHE_HOME/nix-data` (`~/.cache/nix-data`)
+/// and the user config in `$XDG_CONFIG_HOME/nix-data` (`~/.config/nix-data`).
+/// If `HOME` isn't set either, `$XDG_RUNTIME_DIR/nix-data` is used instead,
+/// or a directory only the current user can access in the system temporary directory.
+///
+/// # Example
+/// ```no_run
+/// nix_data::NixData::builder()
+///     .cachedir("/var/cache/my-service")
+///     .configdir("/var/lib/my-service")
+///     .build()
+///     .init();
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NixData {
+    cachedir: String,
+    configdir: String,
+    home: Option<String>,
+}
+
+/// Builder for [NixData]. Directories that aren't set are resolved from the environment.
+#[derive(Debug, Clone, Default)]
+pub struct NixDataBuilder {
+    cachedir: Option<PathBuf>,
+    configdir: Option<PathBuf>,
+    home: Option<PathBuf>,
+}
+
+lazy_static::lazy_static! {
+    static ref CONTEXT: RwLock<NixData> = RwLock::new(NixData::fromenv());
+    static ref PRIVATEDIR: PathBuf = privatedir();
+}
+
+impl NixData {
+    pub fn builder() -> NixDataBuilder {
+        NixDataBuilder::default()
+    }
+
+    /// Resolves all directories from the environment.
+    pub fn fromenv() -> NixData {
+        NixData::builder().build()
+    }
+
+    /// Returns the context currently used by all functions in this crate.
+    pub fn current() -> NixData {
+        CONTEXT.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Makes this the context used by all functions in this crate.
+    pub fn init(self) {
+        *CONTEXT.write().unwrap_or_else(|e| e.into_inner()) = self;
+    }
+
+    /// Directory containing cached package and option data.
+    pub fn cachedir(&self) -> &str {
+        &self.cachedir
+    }
+
+    /// Directory containing the user config file.
+    pub fn configdir(&self) -> &str {
+        &self.configdir
+    }
+
+    /// Home directory of the user, used to find `nix profile` and `nix-env` installations.
+    pub fn home(&self) -> Option<&str> {
+        self.home.as_deref()
+    }
+}
+
+impl NixDataBuilder {
+    /// Sets the directory cached data is stored in.
+    pub fn cachedir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.cachedir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets the directory the user config file is stored in.
+    pub fn configdir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.configdir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets the home directory of the user.
+    pub fn home<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.home = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn build(self) -> NixData {
+        let home = self
+            .home
+            .map(|h| h.to_string_lossy().to_string())
+            .or_else(envhome);
+        let cachedir = match self.cachedir {
+            Some(dir) => dir.to_string_lossy().to_string(),
+            None => xdgdir("XDG_CACHE_HOME", ".cache", "cache", home.as_deref()),
+        };
+        let configdir = match self.configdir {
+            Some(dir) => dir.to_string_lossy().to_string(),
+            None => xdgdir("XDG_CONFIG_HOME", ".config", "config", home.as_deref()),
+        };
+        NixData {
+            cachedir,
+            configdir,
+            home,
+        }
+    }
+}
+
+fn envhome() -> Option<String> {
+    env::var("HOME").ok().filter(|h| !h.is_empty())
+}
+
+/// Returns `${var}/nix-data`, `~/{homedir}/nix-data` or `{private}/{tmpdir}`, whichever is available first,
+/// where `{private}` is a [privatedir()] for the current user.
+/// XDG variables that aren't absolute paths are ignored, as required by the specification.
+fn xdgdir(var: &str, homedir: &str, tmpdir: &str, home: Option<&str>) -> String {
+    if let Some(dir) = env::var(var).ok().filter(|d| Path::new(d).is_absolute()) {
+        format!("{}/nix-data", dir)
+    } else if let Some(home) = home {
+        format!("{}/{}/nix-data", home, homedir)
+    } else {
+        let dir = PRIVATEDIR.join(tmpdir);
+        warn!("HOME is not set, using {}", dir.display());
+        dir.to_string_lossy().to_string()
+    }
+}
+
+/// Directory for data of the current user when `HOME` isn't set.
+/// Uses `$XDG_RUNTIME_DIR/nix-data`, which only the user can access.
+/// Otherwise uses `{tmp}/nix-data-{uid}`, created with mode `0700`.
+/// If that directory already exists but isn't private to the user, such as when another user created it,
+/// a new private directory is created instead.
+fn privatedir() -> PathBuf {
+    if let Some(dir) = env::var("XDG_RUNTIME_DIR")
+        .ok()
+        .filter(|d| Path::new(d).is_absolute())
+    {
+        return PathBuf::from(dir).join("nix-data");
+    }
+    // `/proc/self` is owned by the user the process runs as
+    let uid = fs::metadata("/proc/self").map(|m| m.uid()).unwrap_or(0);
+    let dir = env::temp_dir().join(format!("nix-data-{}", uid));
+    let create = |dir: &Path| fs::DirBuilder::new().mode(0o700).create(dir);
+    if create(&dir).is_ok() || isprivate(&dir, uid) {
+        return dir;
+    }
+    warn!("{} is not private to the current user", dir.display());
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or_default();
+    for n in 0..100 {
+        let fresh = env::temp_dir().join(format!(
+            "nix-data-{}-{}-{}",
+            uid,
+            process::id(),
+            nanos.wrapping_add(n)
+        ));
+        // Creating fails if the directory exists, so a successfully created one can't have been planted by anyone else
+        if create(&fresh).is_ok() {
+            return fresh;
+        }
+    }
+    dir
+}
+
+/// Whether `dir` is a directory, not a symlink, that is owned by `uid` and only accessible by it.
+fn isprivate(dir: &Path, uid: u32) -> bool {
+    fs::symlink_metadata(dir)
+        .map(|m| m.is_dir() && m.uid() == uid && m.mode() & 0o077 == 0)
+        .unwrap_or(false)
+}
+
+pub(crate) fn cachedir() -> String {
+    NixData::current().cachedir
+}
+
+pub(crate) fn configdir() -> String {
+    NixData::current().configdir
+}
+
+/// Path to the user config file.
+pub(crate) fn configfile() -> String {
+    format!("{}/config.json", configdir())
+}
+
+/// Home directory of the user. Errors if it isn't known.
+pub(crate) fn home() -> Result<String> {
+    NixData::current()
+        .home
+        .ok_or_else(|| Error::Config(String::from("HOME is not set")))
+}
+
+/// `$XDG_DATA_HOME`, falling back to `~/.local/share`.
+pub(crate) fn datahome() -> Result<String> {
+    match env::var("XDG_DATA_HOME")
+        .ok()
+        .filter(|d| Path::new(d).is_absolute())
+    {
+        Some(dir) => Ok(dir),
+        None => Ok(format!("{}/.local/share", home()?)),
+    }
+}
+
+/// `$XDG_STATE_HOME`, falling back to `~/.local/state`.
+pub(crate) fn statehome() -> Result<String> {
+    match env::var("XDG_STATE_HOME")
+        .ok()
+        .filter(|d| Path::new(d).is_absolute())
+    {
+        Some(dir) => Ok(dir),
+        None => Ok(format!("{}/.local/state", home()?)),
+    }
+}
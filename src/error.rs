@@ -0,0 +1,109 @@
+use crate::cache::{DownloadError, IntegrityError};
+use std::{fmt, io, string::FromUtf8Error};
+
+/// Errors returned by this crate.
+#[derive(Debug)]
+pub enum Error {
+    /// A mirror couldn't be reached or a request to it failed.
+    Network(reqwest::Error),
+    /// A cache entry couldn't be fetched and there is no previously cached data to fall back to.
+    /// This is usually the case when offline before the cache has been filled.
+    Cache(String),
+    /// Reading or writing a file failed.
+    Io(io::Error),
+    /// A downloaded file couldn't be decompressed or written.
+    Download(DownloadError),
+    /// A downloaded file failed checksum or signature verification.
+    Integrity(IntegrityError),
+    /// A command such as `nix`, `nix-env` or `nixos-version` couldn't be run.
+    Command { command: String, source: io::Error },
+    /// The function only works on NixOS, but the system isn't NixOS.
+    NotNixos,
+    /// Output of a command or contents of a file couldn't be parsed.
+    Parse(String),
+    /// A query on an SQLite database failed.
+    Database(sqlx::Error),
+    /// The config file is missing or invalid.
+    Config(String),
+}
+
+/// Result type used throughout this crate.
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Network(e) => write!(f, "Network error: {}", e),
+            Error::Cache(e) => write!(f, "{}", e),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Download(e) => write!(f, "{}", e),
+            Error::Integrity(e) => write!(f, "{}", e),
+            Error::Command { command, source } => {
+                write!(f, "Failed to run `{}`: {}", command, source)
+            }
+            Error::NotNixos => write!(f, "Not a NixOS system"),
+            Error::Parse(e) => write!(f, "Parse error: {}", e),
+            Error::Database(e) => write!(f, "Database error: {}", e),
+            Error::Config(e) => write!(f, "Config error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Network(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Download(e) => Some(e),
+            Error::Integrity(e) => Some(e),
+            Error::Command { source, .. } => Some(source),
+            Error::Database(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Network(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<DownloadError> for Error {
+    fn from(e: DownloadError) -> Self {
+        match e {
+            DownloadError::Integrity(e) => Error::Integrity(e),
+            e => Error::Download(e),
+        }
+    }
+}
+
+impl From<IntegrityError> for Error {
+    fn from(e: IntegrityError) -> Self {
+        Error::Integrity(e)
+    }
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(e: sqlx::Error) -> Self {
+        Error::Database(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Parse(e.to_string())
+    }
+}
+
+impl From<FromUtf8Error> for Error {
+    fn from(e: FromUtf8Error) -> Self {
+        Error::Parse(e.to_string())
+    }
+}
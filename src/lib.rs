@@ -1,18 +1,21 @@
 //! A set of modules for easily managing Nix and NixOS packages and options.
-//! 
+//!
 //! This crate contains a [cache](crate::cache) module for caching Nix/NixOS packages and options,
 //! such as the latest `packages.json` and `options.json` from the NixOS cache.
-//! 
+//!
 //! This crate also contains a [config](crate::config) module for maintaining a set of important Nix/NixOS details,
 //! such as the location of the users `configuration.nix` file, and whether they are using flakes or not.
 //! This can be useful so that not ever application/utility needs to maintain their own config files and preferences.
-//! 
+//!
+//! Cache and config files are stored in the XDG base directories by default, which can be changed with [NixData].
+//!
 //! # Example
-//! ```
+//! ```no_run
 //! extern crate nix_data;
-//!  
-//! fn main() {
-//!     let userpkgs = nix_data::cache::profile::getprofilepkgs_versioned();
+//!
+//! #[tokio::main]
+//! async fn main() {
+//!     let userpkgs = nix_data::cache::profile::getprofilepkgs_versioned(None).await;
 //!     if let Ok(pkgs) = userpkgs {
 //!         println!("List of installed nix profile packages");
 //!         println!("===");
@@ -27,13 +30,22 @@
 pub mod cache;
 /// A module for managing the configuration containing user and system options.
 pub mod config;
+mod context;
+mod error;
+/// A module for listing and searching NixOS and Home Manager options.
+pub mod options;
+/// A module for finding installed packages with a newer version available.
+pub mod outdated;
+/// A module for searching packages in the cached package databases.
+pub mod search;
+/// A module for parsing Nix store paths.
+pub mod storepath;
 
 pub mod utils;
+/// A module for comparing Nix package versions.
+pub mod version;
+
+pub use context::{NixData, NixDataBuilder};
+pub use error::{Error, Result};
 
-lazy_static::lazy_static! {
-    static ref CACHEDIR: String = format!("{}/.cache/nix-data", std::env::var("HOME").unwrap());
-    static ref CONFIGDIR: String = format!("{}/.config/nix-data", std::env::var("HOME").unwrap());
-    static ref CONFIG: String = format!("{}/config.json", &*CONFIGDIR);
-    static ref HOME: String = std::env::var("HOME").unwrap();
-}
 static SYSCONFIG: &str = "/etc/nix-data/config.json";
@@ -0,0 +1,311 @@
+use crate::{
+    cache::{builddb, homemanager, nixos, CachedFile},
+    Error, Result,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use sqlx::{sqlite::SqliteConnectOptions, Connection, SqlitePool};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs::File,
+    io::BufReader,
+    path::Path,
+};
+
+/// An option from an `options.json` file, such as the one returned by [nixosoptions()](crate::cache::nixos::nixosoptions).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NixOption {
+    /// Full name of the option, such as `services.nginx.enable`.
+    pub name: String,
+    /// Description of the option type, such as `boolean`.
+    pub optiontype: Option<String>,
+    pub description: Option<String>,
+    /// Default value as a Nix expression, or as JSON if `options.json` only has the evaluated value.
+    pub default: Option<String>,
+    /// Example value, in the same format as `default`.
+    pub example: Option<String>,
+    /// Files declaring the option.
+    pub declarations: Vec<String>,
+    pub readonly: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct OptionOut {
+    #[serde(rename = "type")]
+    optiontype: Option<String>,
+    description: Option<Value>,
+    default: Option<Value>,
+    example: Option<Value>,
+    #[serde(default)]
+    declarations: Vec<Value>,
+    #[serde(rename = "readOnly", default)]
+    readonly: bool,
+}
+
+#[derive(sqlx::FromRow)]
+struct OptionRow {
+    name: String,
+    optiontype: Option<String>,
+    description: Option<String>,
+    defaultvalue: Option<String>,
+    example: Option<String>,
+    declarations: String,
+    readonly: bool,
+}
+
+/// Indexed database of the options in an `options.json` file.
+/// The database is stored next to the file and rebuilt when the file changes.
+///
+/// # Example
+/// ```no_run
+/// # async fn example() -> nix_data::Result<()> {
+/// let options = nix_data::options::index().await?;
+/// for child in options.children("services.nginx").await? {
+///     println!("services.nginx.{}", child);
+/// }
+/// for option in options.search("nginx virtualhost", 10).await? {
+///     println!("{}: {}", option.name, option.description.unwrap_or_default());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct OptionsDb {
+    pool: SqlitePool,
+}
+
+/// Opens the options database of the latest NixOS options from [nixosoptions()](crate::cache::nixos::nixosoptions).
+pub async fn index() -> Result<OptionsDb> {
+    OptionsDb::open(&nixos::nixosoptions().await?).await
+}
+
+/// Opens the options database of the latest Home Manager options from [homemanageroptions()](crate::cache::homemanager::homemanageroptions).
+pub async fn homemanagerindex() -> Result<OptionsDb> {
+    OptionsDb::open(&homemanager::homemanageroptions().await?).await
+}
+
+impl OptionsDb {
+    /// Opens the database for the options file `options`, building it first if needed.
+    pub async fn open(options: &CachedFile) -> Result<OptionsDb> {
+        let path = builddb::derived(options, "json", "index", |name, dbfile| async move {
+            let file = options.path.to_string();
+            let parsed = tokio::task::spawn_blocking(move || parse(&file))
+                .await
+                .map_err(|e| Error::Cache(e.to_string()))??;
+            build(&name, &dbfile, &parsed).await
+        })
+        .await?;
+
+        let pool = SqlitePool::connect_with(SqliteConnectOptions::new().filename(&path)).await?;
+        Ok(OptionsDb { pool })
+    }
+
+    /// Returns the option named `name`, if it exists.
+    pub async fn get(&self, name: &str) -> Result<Option<NixOption>> {
+        let row: Option<OptionRow> = sqlx::query_as(
+            r#"
+            SELECT name, optiontype, description, defaultvalue, example, declarations, readonly
+            FROM options WHERE name = $1
+            "#,
+        )
+        .bind(name)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(NixOption::from))
+    }
+
+    /// Returns the option `path` and all options below it, sorted by name.
+    /// An empty `path` returns all options.
+    pub async fn subtree(&self, path: &str) -> Result<Vec<NixOption>> {
+        let (lower, upper) = range(path);
+        let rows: Vec<OptionRow> = sqlx::query_as(
+            r#"
+            SELECT name, optiontype, description, defaultvalue, example, declarations, readonly
+            FROM options WHERE name = $1 OR (name >= $2 AND name < $3)
+            ORDER BY name
+            "#,
+        )
+        .bind(path)
+        .bind(lower)
+        .bind(upper)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(NixOption::from).collect())
+    }
+
+    /// Returns the names of the next level below `path`, such as `enable` and `virtualHosts` for `services.nginx`.
+    /// An empty `path` returns the top-level names.
+    pub async fn children(&self, path: &str) -> Result<Vec<String>> {
+        let (lower, upper) = range(path);
+        let names: Vec<(String,)> =
+            sqlx::query_as("SELECT name FROM options WHERE name >= $1 AND name < $2")
+                .bind(&lower)
+                .bind(upper)
+                .fetch_all(&self.pool)
+                .await?;
+        let skip = lower.len();
+        Ok(names
+            .iter()
+            .filter_map(|(name,)| name.get(skip..)?.split('.').next())
+            .map(|child| child.to_string())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect())
+    }
+
+    /// Searches for options whose name or description match all words in `query`, best matches first.
+    /// Words match as prefixes.
+    pub async fn search(&self, query: &str, limit: usize) -> Result<Vec<NixOption>> {
+        let terms = query
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|t| !t.is_empty())
+            .map(|t| format!("\"{}\"*", t.to_lowercase()))
+            .collect::<Vec<_>>();
+        if terms.is_empty() {
+            return Ok(vec![]);
+        }
+        let rows: Vec<OptionRow> = sqlx::query_as(
+            r#"
+            SELECT o.name, o.optiontype, o.description, o.defaultvalue, o.example, o.declarations, o.readonly
+            FROM search JOIN options o ON o.rowid = search.rowid
+            WHERE search MATCH $1
+            ORDER BY lower(o.name) = $2 DESC, bm25(search, 10.0, 1.0), length(o.name)
+            LIMIT $3
+            "#,
+        )
+        .bind(terms.join(" AND "))
+        .bind(query.trim().to_lowercase())
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(NixOption::from).collect())
+    }
+}
+
+/// Range of names below `path`, so the lookup can use the index on `name`.
+fn range(path: &str) -> (String, String) {
+    if path.is_empty() {
+        (String::new(), String::from(char::MAX))
+    } else {
+        // `/` comes right after `.`
+        (format!("{}.", path), format!("{}/", path))
+    }
+}
+
+/// Parses the options file at `path`.
+fn parse(path: &str) -> Result<Vec<NixOption>> {
+    let options: HashMap<String, OptionOut> =
+        serde_json::from_reader(BufReader::new(File::open(path)?))?;
+    Ok(options
+        .into_iter()
+        .map(|(name, option)| NixOption {
+            name,
+            optiontype: option.optiontype,
+            description: option.description.as_ref().and_then(text),
+            default: option.default.as_ref().and_then(text),
+            example: option.example.as_ref().and_then(text),
+            declarations: option
+                .declarations
+                .iter()
+                .filter_map(|d| match d {
+                    Value::Object(d) => d.get("name").and_then(text),
+                    d => text(d),
+                })
+                .collect(),
+            readonly: option.readonly,
+        })
+        .collect())
+}
+
+/// Returns the text of a value in `options.json`.
+/// Values such as `literalExpression` and `mdDoc` are objects with a `_type` and the `text`,
+/// other values are returned as JSON.
+fn text(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::String(s) => Some(s.to_string()),
+        Value::Object(o) if o.contains_key("_type") => o
+            .get("text")
+            .and_then(|t| t.as_str())
+            .map(|t| t.to_string()),
+        value => Some(value.to_string()),
+    }
+}
+
+/// Builds the options database for `options` at `dbfile`.
+async fn build(entry: &str, dbfile: &Path, options: &[NixOption]) -> Result<()> {
+    let mut conn = builddb::create(dbfile).await?;
+    let mut tx = conn.begin().await?;
+    sqlx::query(
+        r#"
+        CREATE TABLE options (
+            rowid INTEGER PRIMARY KEY,
+            name TEXT NOT NULL UNIQUE,
+            optiontype TEXT,
+            description TEXT,
+            defaultvalue TEXT,
+            example TEXT,
+            declarations TEXT NOT NULL,
+            readonly INTEGER NOT NULL
+        )
+        "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    builddb::insert(
+        &mut tx,
+        entry,
+        "options",
+        &[
+            "name",
+            "optiontype",
+            "description",
+            "defaultvalue",
+            "example",
+            "declarations",
+            "readonly",
+        ],
+        options,
+        |query, option| {
+            Ok(query
+                .bind(&option.name)
+                .bind(&option.optiontype)
+                .bind(&option.description)
+                .bind(&option.default)
+                .bind(&option.example)
+                .bind(serde_json::to_string(&option.declarations)?)
+                .bind(option.readonly))
+        },
+    )
+    .await?;
+
+    for statement in [
+        r#"
+        CREATE VIRTUAL TABLE search USING fts5(
+            name, description,
+            content = 'options', content_rowid = 'rowid'
+        )
+        "#,
+        "INSERT INTO search(search) VALUES ('rebuild')",
+    ] {
+        sqlx::query(statement).execute(&mut *tx).await?;
+    }
+    tx.commit().await?;
+    conn.close().await?;
+    Ok(())
+}
+
+impl From<OptionRow> for NixOption {
+    fn from(row: OptionRow) -> Self {
+        NixOption {
+            name: row.name,
+            optiontype: row.optiontype,
+            description: row.description,
+            default: row.defaultvalue,
+            example: row.example,
+            declarations: serde_json::from_str(&row.declarations).unwrap_or_default(),
+            readonly: row.readonly,
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use crate::{
+    cache::{
+        channel,
+        db::{self, PackageDb},
+        flakes, nixos, profile,
+    },
+    config::configfile::getconfigordefault,
+    version::{Bump, NixVersion},
+    Error, Result,
+};
+use std::collections::HashMap;
+
+/// Where an installed package comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InstallSource {
+    /// Installed with `nix profile`.
+    Profile,
+    /// Installed with `nix-env`.
+    Env,
+    /// Listed in `environment.systemPackages` of the NixOS configuration.
+    System,
+}
+
+/// An installed package with a different version available.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutdatedPkg {
+    /// Attribute of the package.
+    pub name: String,
+    pub installed: NixVersion,
+    pub available: NixVersion,
+    pub bump: Bump,
+}
+
+/// Returns the outdated packages of every installation source used on this system.
+///
+/// System packages are read from the `systemconfig` file in the [config](crate::config),
+/// using the flake versions if a `flake` is configured.
+/// Sources that don't apply, such as system packages outside of NixOS or `nix-env` when it isn't installed, are left out.
+///
+/// # Example
+/// ```no_run
+/// # async fn example() -> nix_data::Result<()> {
+/// for (source, pkgs) in nix_data::outdated::outdated().await? {
+///     for pkg in pkgs {
+///         println!("{:?} {}: {} -> {} ({:?})", source, pkg.name, pkg.installed, pkg.available, pkg.bump);
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub async fn outdated() -> Result<HashMap<InstallSource, Vec<OutdatedPkg>>> {
+    let mut out = HashMap::new();
+    out.insert(InstallSource::Profile, outdatedprofile().await?);
+    match outdatedenv().await {
+        Ok(pkgs) => {
+            out.insert(InstallSource::Env, pkgs);
+        }
+        Err(Error::Command { .. }) => {}
+        Err(e) => return Err(e),
+    }
+    let config = getconfigordefault()?;
+    if let Some(systemconfig) = config.systemconfig {
+        match outdatedsystem(&[&systemconfig], config.flake.is_some()).await {
+            Ok(pkgs) => {
+                out.insert(InstallSource::System, pkgs);
+            }
+            Err(Error::NotNixos) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(out)
+}
+
+/// Returns the outdated packages installed with `nix profile` in the default profile,
+/// compared to the versions from [nixpkgslatest()](crate::cache::profile::nixpkgslatest).
+/// Packages whose store path has no version are left out, as there is nothing to compare.
+/// Packages from other flakes are left out as well, use [getflakerevisions()](crate::cache::profile::getflakerevisions) for them.
+/// Returns no packages if there is no default profile, such as when the home directory isn't known.
+pub async fn outdatedprofile() -> Result<Vec<OutdatedPkg>> {
+    let profile = match profile::defaultprofile() {
+        Ok(Some(profile)) => profile,
+        Ok(None) | Err(Error::Config(_)) => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let installed = profile::getprofilepkgs(Some(&profile))?
+        .into_iter()
+        .filter(|(attr, _)| !profile::isflakepkg(attr))
+        .filter_map(|(attr, pkg)| Some((attr, pkg.storepath.version()?.to_string())))
+        .collect::<HashMap<_, _>>();
+    let db = PackageDb::open(profile::nixpkgslatest().await?).await?;
+    let available = db.versions(&installed.keys().collect::<Vec<_>>()).await?;
+    Ok(compare(installed, &available))
+}
+
+/// Returns the outdated packages installed with `nix-env`,
+/// compared to the package database for the current system.
+/// Packages are matched to attributes with [getenvpkgs_resolved()](crate::cache::channel::getenvpkgs_resolved),
+/// those that can't be resolved to a single attribute are left out.
+pub async fn outdatedenv() -> Result<Vec<OutdatedPkg>> {
+    let installed = channel::getenvpkgs_resolved()
+        .await?
+        .into_values()
+        .filter_map(|pkg| Some((pkg.resolution.attribute()?.to_string(), pkg.version)))
+        .collect::<HashMap<_, _>>();
+    let db = PackageDb::open(db::systempkgs().await?).await?;
+    let available = db.versions(&installed.keys().collect::<Vec<_>>()).await?;
+    Ok(compare(installed, &available))
+}
+
+/// Returns the outdated packages in `environment.systemPackages`, compared to the latest [nixospkgs()](crate::cache::nixos::nixospkgs).
+/// The input `paths` should be the paths to the `configuration.nix` files containing `environment.systemPackages`,
+/// and `flake` whether the system is built from a flake.
+pub async fn outdatedsystem(paths: &[&str], flake: bool) -> Result<Vec<OutdatedPkg>> {
+    let installed = if flake {
+        flakes::getflakepkgs(paths).await?
+    } else {
+        channel::getlegacypkgs(paths).await?
+    };
+    let db = PackageDb::open(nixos::nixospkgs().await?).await?;
+    let available = db.versions(&installed.keys().collect::<Vec<_>>()).await?;
+    Ok(compare(installed, &available))
+}
+
+/// Pairs `installed` versions with `available` ones, keeping packages whose versions differ.
+fn compare(
+    installed: HashMap<String, String>,
+    available: &HashMap<String, String>,
+) -> Vec<OutdatedPkg> {
+    let mut out = installed
+        .into_iter()
+        .filter_map(|(name, installed)| {
+            let installed = NixVersion::from(installed);
+            let available = NixVersion::from(available.get(&name)?.as_str());
+            let bump = installed.bump(&available)?;
+            Some(OutdatedPkg {
+                name,
+                installed,
+                available,
+                bump,
+            })
+        })
+        .collect::<Vec<_>>();
+    out.sort_by(|a, b| a.name.cmp(&b.name));
+    out
+}
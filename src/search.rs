@@ -0,0 +1,368 @@
+use crate::{
+    cache::{builddb, db, CachedFile},
+    Result,
+};
+use log::debug;
+use sqlx::{sqlite::SqliteConnectOptions, Connection, SqlitePool};
+use std::path::Path;
+
+/// Restricts search results by package status.
+/// Each filter is either unset, `Some(false)` to exclude matching packages or `Some(true)` to only include them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SearchFilter {
+    pub broken: Option<bool>,
+    pub insecure: Option<bool>,
+    pub unfree: Option<bool>,
+}
+
+/// A package found by [SearchIndex::search()].
+#[derive(Debug, Clone, PartialEq, sqlx::FromRow)]
+pub struct SearchResult {
+    pub attribute: String,
+    pub pname: String,
+    pub version: String,
+    pub description: Option<String>,
+    pub broken: bool,
+    pub insecure: bool,
+    pub unfree: bool,
+    /// Relevance of the result. Higher is better.
+    pub score: f64,
+}
+
+/// Full-text search index over the attribute, pname and description of all packages in a package database.
+/// The index is stored next to the database and rebuilt when the database changes.
+///
+/// # Example
+/// ```no_run
+/// # async fn example() -> nix_data::Result<()> {
+/// use nix_data::search::{SearchFilter, SearchIndex};
+///
+/// let index = SearchIndex::open(&nix_data::cache::nixos::nixospkgs().await?).await?;
+/// let filter = SearchFilter {
+///     broken: Some(false),
+///     ..Default::default()
+/// };
+/// for result in index.search("firefx", &filter, 10).await? {
+///     println!("{} {}", result.attribute, result.version);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct SearchIndex {
+    pool: SqlitePool,
+}
+
+/// Opens the search index of the package database for the current system,
+/// [nixospkgs()](crate::cache::nixos::nixospkgs) on NixOS and [nixpkgs()](crate::cache::nonnixos::nixpkgs) otherwise.
+pub async fn index() -> Result<SearchIndex> {
+    SearchIndex::open(&db::systempkgs().await?).await
+}
+
+impl SearchIndex {
+    /// Opens the search index for the package database `pkgs`, building it first if needed.
+    pub async fn open(pkgs: &CachedFile) -> Result<SearchIndex> {
+        let path = builddb::derived(pkgs, "db", "search", |_, dbfile| async move {
+            build(&pkgs.path, &dbfile).await
+        })
+        .await?;
+
+        let pool = SqlitePool::connect_with(SqliteConnectOptions::new().filename(&path)).await?;
+        Ok(SearchIndex { pool })
+    }
+
+    /// Searches for packages matching all words in `query`, best matches first.
+    /// Words match as prefixes, and words without any match are replaced with similarly spelled ones.
+    pub async fn search(
+        &self,
+        query: &str,
+        filter: &SearchFilter,
+        limit: usize,
+    ) -> Result<Vec<SearchResult>> {
+        let terms = query
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|t| !t.is_empty())
+            .map(|t| t.to_lowercase())
+            .collect::<Vec<_>>();
+        if terms.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut matches = vec![];
+        for term in &terms {
+            let mut alternatives = vec![format!("\"{}\"*", term)];
+            if !self.hasprefix(term).await? {
+                for similar in self.similar(term).await? {
+                    debug!("Searching for {} instead of {}", similar, term);
+                    alternatives.push(format!("\"{}\"", similar));
+                }
+            }
+            matches.push(format!("({})", alternatives.join(" OR ")));
+        }
+        let fts = matches.join(" AND ");
+
+        let results: Vec<SearchResult> = sqlx::query_as(
+            r#"
+            SELECT p.attribute, coalesce(p.pname, '') AS pname, coalesce(p.version, '') AS version,
+                p.description, coalesce(p.broken, 0) AS broken, coalesce(p.insecure, 0) AS insecure,
+                coalesce(p.unfree, 0) AS unfree, -bm25(search, 10.0, 5.0, 1.0) AS score
+            FROM search JOIN pkgs p ON p.rowid = search.rowid
+            WHERE search MATCH $1
+                AND ($2 IS NULL OR coalesce(p.broken, 0) = $2)
+                AND ($3 IS NULL OR coalesce(p.insecure, 0) = $3)
+                AND ($4 IS NULL OR coalesce(p.unfree, 0) = $4)
+            ORDER BY (lower(p.pname) = $5 OR lower(p.attribute) = $5) DESC, score DESC, length(p.attribute)
+            LIMIT $6
+            "#,
+        )
+        .bind(&fts)
+        .bind(filter.broken)
+        .bind(filter.insecure)
+        .bind(filter.unfree)
+        .bind(terms.join(" "))
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(results)
+    }
+
+    /// Whether any indexed word starts with `term`.
+    async fn hasprefix(&self, term: &str) -> Result<bool> {
+        let next: Option<(String,)> =
+            sqlx::query_as("SELECT term FROM vocab WHERE term >= $1 ORDER BY term LIMIT 1")
+                .bind(term)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(next.map(|(t,)| t.starts_with(term)).unwrap_or(false))
+    }
+
+    /// Indexed words within a small edit distance of `term`, most common first.
+    async fn similar(&self, term: &str) -> Result<Vec<String>> {
+        let len = term.chars().count();
+        let maxdistance = match len {
+            0..=2 => return Ok(vec![]),
+            3..=5 => 1,
+            _ => 2,
+        };
+        let candidates: Vec<(String, i64)> =
+            sqlx::query_as("SELECT term, doc FROM vocab WHERE length(term) BETWEEN $1 AND $2")
+                .bind((len - maxdistance) as i64)
+                .bind((len + maxdistance) as i64)
+                .fetch_all(&self.pool)
+                .await?;
+        let mut similar = candidates
+            .into_iter()
+            .filter_map(|(candidate, docs)| {
+                let distance = editdistance(term, &candidate);
+                (distance <= maxdistance).then_some((distance, -docs, candidate))
+            })
+            .collect::<Vec<_>>();
+        similar.sort();
+        Ok(similar.into_iter().take(5).map(|(_, _, t)| t).collect())
+    }
+}
+
+/// Builds the search index for the package database `pkgsdb` at `dbfile`.
+async fn build(pkgsdb: &str, dbfile: &Path) -> Result<()> {
+    let mut conn = builddb::create(dbfile).await?;
+    sqlx::query("ATTACH DATABASE $1 AS src")
+        .bind(pkgsdb)
+        .execute(&mut conn)
+        .await?;
+    let columns = db::Columns::read(&mut conn, "src").await?;
+    let select = [
+        ("pkgs", "pname", "p"),
+        ("pkgs", "version", "p"),
+        ("meta", "description", "m"),
+        ("meta", "broken", "m"),
+        ("meta", "insecure", "m"),
+    ]
+    .iter()
+    .map(|(table, column, alias)| columns.select(table, column, alias))
+    .chain([columns.unfree("m")])
+    .collect::<Vec<_>>()
+    .join(", ");
+    let insert = format!(
+        "INSERT INTO pkgs (attribute, pname, version, description, broken, insecure, unfree) SELECT p.attribute, {} FROM src.pkgs p {}",
+        select,
+        if columns.hasmeta() {
+            "LEFT JOIN src.meta m ON p.attribute = m.attribute"
+        } else {
+            ""
+        }
+    );
+
+    let mut tx = conn.begin().await?;
+    for statement in [
+        r#"
+        CREATE TABLE pkgs (
+            rowid INTEGER PRIMARY KEY,
+            attribute TEXT NOT NULL,
+            pname TEXT,
+            version TEXT,
+            description TEXT,
+            broken INTEGER,
+            insecure INTEGER,
+            unfree INTEGER
+        )
+        "#,
+        &insert,
+        r#"
+        CREATE VIRTUAL TABLE search USING fts5(
+            attribute, pname, description,
+            content = 'pkgs', content_rowid = 'rowid'
+        )
+        "#,
+        "INSERT INTO search(search) VALUES ('rebuild')",
+        "CREATE VIRTUAL TABLE vocab USING fts5vocab(search, row)",
+    ] {
+        sqlx::query(statement).execute(&mut *tx).await?;
+    }
+    tx.commit().await?;
+    sqlx::query("DETACH DATABASE src")
+        .execute(&mut conn)
+        .await?;
+    conn.close().await?;
+    Ok(())
+}
+
+/// Levenshtein distance between `a` and `b`.
+fn editdistance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut prev = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            cur[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Builds a search index over a small package database in `dir`.
+    async fn index(dir: &TempDir) -> SearchIndex {
+        let pkgsdb = dir.path().join("pkgs.db");
+        let mut conn = builddb::create(&pkgsdb).await.unwrap();
+        for statement in [
+            "CREATE TABLE pkgs (attribute TEXT PRIMARY KEY, pname TEXT, version TEXT)",
+            "CREATE TABLE meta (attribute TEXT PRIMARY KEY, description TEXT, broken INTEGER, insecure INTEGER, license TEXT)",
+            r#"INSERT INTO pkgs VALUES
+                ('firefox', 'firefox', '118.0'),
+                ('firefox-esr', 'firefox-esr', '115.3.1'),
+                ('thunderbird', 'thunderbird', '115.3.1'),
+                ('hello', 'hello', '2.12.1'),
+                ('vscode', 'vscode', '1.83.0')"#,
+            r#"INSERT INTO meta VALUES
+                ('firefox', 'A web browser built from Firefox source tree', 0, 0, '{"free": true}'),
+                ('firefox-esr', 'A web browser built from Firefox source tree', 0, 1, '{"free": true}'),
+                ('thunderbird', 'A full-featured e-mail client', 1, 0, '{"free": true}'),
+                ('hello', 'A program that produces a familiar, friendly greeting', 0, 0, '[{"free": true}]'),
+                ('vscode', 'Code editor redefined and optimized for building web applications', 0, 0, '{"free": false}')"#,
+        ] {
+            sqlx::query(statement).execute(&mut conn).await.unwrap();
+        }
+        conn.close().await.unwrap();
+
+        let indexdb = dir.path().join("search.db");
+        build(&pkgsdb.to_string_lossy(), &indexdb).await.unwrap();
+        let pool = SqlitePool::connect_with(SqliteConnectOptions::new().filename(&indexdb))
+            .await
+            .unwrap();
+        SearchIndex { pool }
+    }
+
+    async fn attributes(index: &SearchIndex, query: &str, filter: &SearchFilter) -> Vec<String> {
+        index
+            .search(query, filter, 10)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|r| r.attribute)
+            .collect()
+    }
+
+    #[test]
+    fn measureseditdistance() {
+        assert_eq!(editdistance("", ""), 0);
+        assert_eq!(editdistance("hello", "hello"), 0);
+        assert_eq!(editdistance("", "abc"), 3);
+        assert_eq!(editdistance("firefx", "firefox"), 1);
+        assert_eq!(editdistance("frefox", "firefox"), 1);
+        assert_eq!(editdistance("fierfox", "firefox"), 2);
+        assert_eq!(editdistance("kitten", "sitting"), 3);
+        assert_eq!(editdistance("über", "uber"), 1);
+    }
+
+    #[tokio::test]
+    async fn searchesbyprefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = index(&dir).await;
+        let filter = SearchFilter::default();
+        // Exact names come first
+        assert_eq!(
+            attributes(&index, "firefox", &filter).await,
+            ["firefox", "firefox-esr"]
+        );
+        assert_eq!(
+            attributes(&index, "thunder", &filter).await,
+            ["thunderbird"]
+        );
+        assert_eq!(attributes(&index, "web brow", &filter).await.len(), 2);
+        assert_eq!(attributes(&index, "web code", &filter).await, ["vscode"]);
+        assert!(attributes(&index, "  ", &filter).await.is_empty());
+        assert!(attributes(&index, "nothing", &filter).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn searchesbysimilarwords() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = index(&dir).await;
+        let filter = SearchFilter::default();
+        assert_eq!(
+            attributes(&index, "firefx", &filter).await,
+            ["firefox", "firefox-esr"]
+        );
+        assert_eq!(attributes(&index, "helo", &filter).await, ["hello"]);
+        assert_eq!(
+            attributes(&index, "thnderbrd", &filter).await,
+            ["thunderbird"]
+        );
+        // Short words are not corrected
+        assert!(attributes(&index, "hx", &filter).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn filtersresults() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = index(&dir).await;
+        let secure = SearchFilter {
+            insecure: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(attributes(&index, "firefox", &secure).await, ["firefox"]);
+        let broken = SearchFilter {
+            broken: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(attributes(&index, "client", &broken).await, ["thunderbird"]);
+        let unfree = SearchFilter {
+            unfree: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(attributes(&index, "web", &unfree).await, ["vscode"]);
+        let results = index
+            .search("hello", &SearchFilter::default(), 10)
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].version, "2.12.1");
+        assert!(!results[0].unfree);
+    }
+}
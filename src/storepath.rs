@@ -0,0 +1,234 @@
+use crate::{version::parsedrvname, Error, Result};
+use std::{fmt, path::Path, str::FromStr};
+
+/// Characters of the base-32 alphabet Nix uses for store path hashes.
+const HASHCHARS: &str = "0123456789abcdfghijklmnpqrsvwxyz";
+
+/// Length of the hash part of a store path.
+const HASHLEN: usize = 32;
+
+/// Names of outputs that are appended to the name of a store path, such as `-man` in `nix-2.18.1-man`.
+const OUTPUTS: [&str; 11] = [
+    "bin", "debug", "dev", "devdoc", "doc", "info", "lib", "man", "py", "static", "terminfo",
+];
+
+/// A path in the Nix store, such as `/nix/store/<hash>-hello-2.12.1`.
+///
+/// # Example
+/// ```
+/// use nix_data::storepath::StorePath;
+///
+/// let path: StorePath = "/nix/store/0c8b5csxrk5ngn8jqzf9cmd3x2iky4h9-nix-2.18.1-man".parse()?;
+/// assert_eq!(path.pname(), "nix");
+/// assert_eq!(path.version(), Some("2.18.1"));
+/// assert_eq!(path.output(), Some("man"));
+/// # Ok::<(), nix_data::Error>(())
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct StorePath {
+    storedir: String,
+    hash: String,
+    name: String,
+    /// Length of `name` without the output suffix.
+    drvnamelen: usize,
+}
+
+impl StorePath {
+    /// Parses the store path `path`. The store directory can be anything, such as `/nix/store` or `/home/user/nix/store`.
+    pub fn parse(path: &str) -> Result<StorePath> {
+        let invalid =
+            |reason: &str| Error::Parse(format!("Invalid store path {}: {}", path, reason));
+        let trimmed = path.trim_end_matches('/');
+        let (storedir, basename) = trimmed
+            .rsplit_once('/')
+            .ok_or_else(|| invalid("not an absolute path"))?;
+        if !Path::new(trimmed).is_absolute() {
+            return Err(invalid("not an absolute path"));
+        }
+        let (hash, name) = basename
+            .split_once('-')
+            .ok_or_else(|| invalid("no name after the hash"))?;
+        if hash.len() != HASHLEN || !hash.chars().all(|c| HASHCHARS.contains(c)) {
+            return Err(invalid("malformed hash"));
+        }
+        if name.is_empty()
+            || name.starts_with('.')
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "+-._?=".contains(c))
+        {
+            return Err(invalid("malformed name"));
+        }
+
+        // Only treat a known output name as a suffix if a version comes before it,
+        // as packages such as `foo-man` are more likely named that way
+        let drvnamelen = match name.rsplit_once('-') {
+            _ if name.ends_with(".drv") => name.len() - ".drv".len(),
+            Some((drvname, output))
+                if OUTPUTS.contains(&output) && !parsedrvname(drvname).1.is_empty() =>
+            {
+                drvname.len()
+            }
+            _ => name.len(),
+        };
+        Ok(StorePath {
+            storedir: storedir.to_string(),
+            hash: hash.to_string(),
+            name: name.to_string(),
+            drvnamelen,
+        })
+    }
+
+    /// Directory of the store, such as `/nix/store`.
+    pub fn storedir(&self) -> &str {
+        &self.storedir
+    }
+
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    /// Full name after the hash, such as `nix-2.18.1-man`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Name of the derivation without the output or `.drv` suffix, such as `nix-2.18.1`.
+    pub fn drvname(&self) -> &str {
+        &self.name[..self.drvnamelen]
+    }
+
+    /// Package name, such as `nix`.
+    pub fn pname(&self) -> &str {
+        parsedrvname(self.drvname()).0
+    }
+
+    /// Package version, such as `2.18.1`. `None` for paths without one, such as `hello.sh`.
+    pub fn version(&self) -> Option<&str> {
+        Some(parsedrvname(self.drvname()).1).filter(|v| !v.is_empty())
+    }
+
+    /// Output the path belongs to, such as `man`. `None` for the default output and derivations.
+    pub fn output(&self) -> Option<&str> {
+        if self.isderivation() {
+            return None;
+        }
+        self.name.get(self.drvnamelen + 1..)
+    }
+
+    /// Whether the path is a store derivation, such as `nix-2.18.1.drv`.
+    pub fn isderivation(&self) -> bool {
+        self.name.ends_with(".drv")
+    }
+}
+
+impl fmt::Display for StorePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}-{}", self.storedir, self.hash, self.name)
+    }
+}
+
+impl FromStr for StorePath {
+    type Err = Error;
+
+    fn from_str(path: &str) -> Result<Self> {
+        StorePath::parse(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "0c8b5csxrk5ngn8jqzf9cmd3x2iky4h9";
+
+    fn parse(name: &str) -> Result<StorePath> {
+        StorePath::parse(&format!("/nix/store/{}-{}", HASH, name))
+    }
+
+    #[test]
+    fn validateshashes() {
+        for path in [
+            // Too short
+            "/nix/store/0c8b5csxrk5ngn8jqzf9cmd3x2iky4h-hello-2.12.1",
+            // Too long
+            "/nix/store/0c8b5csxrk5ngn8jqzf9cmd3x2iky4h9a-hello-2.12.1",
+            // `e`, `o`, `u` and `t` are not in the alphabet
+            "/nix/store/ec8b5csxrk5ngn8jqzf9cmd3x2iky4h9-hello-2.12.1",
+            "/nix/store/0C8B5CSXRK5NGN8JQZF9CMD3X2IKY4H9-hello-2.12.1",
+            // No name
+            "/nix/store/0c8b5csxrk5ngn8jqzf9cmd3x2iky4h9",
+            "/nix/store/0c8b5csxrk5ngn8jqzf9cmd3x2iky4h9-",
+            // Not absolute
+            "nix/store/0c8b5csxrk5ngn8jqzf9cmd3x2iky4h9-hello-2.12.1",
+            "0c8b5csxrk5ngn8jqzf9cmd3x2iky4h9-hello-2.12.1",
+        ] {
+            assert!(StorePath::parse(path).is_err(), "{}", path);
+        }
+        assert!(parse(".hidden").is_err());
+        assert!(parse("hello world").is_err());
+
+        let path =
+            StorePath::parse(&format!("/home/user/nix/store/{}-hello-2.12.1/", HASH)).unwrap();
+        assert_eq!(path.storedir(), "/home/user/nix/store");
+        assert_eq!(path.hash(), HASH);
+        assert_eq!(path.name(), "hello-2.12.1");
+        assert_eq!(
+            path.to_string(),
+            format!("/home/user/nix/store/{}-hello-2.12.1", HASH)
+        );
+    }
+
+    #[test]
+    fn parsesnames() {
+        // (name, drvname, pname, version, output)
+        for (name, drvname, pname, version, output) in [
+            (
+                "hello-2.12.1",
+                "hello-2.12.1",
+                "hello",
+                Some("2.12.1"),
+                None,
+            ),
+            (
+                "nix-2.18.1-man",
+                "nix-2.18.1",
+                "nix",
+                Some("2.18.1"),
+                Some("man"),
+            ),
+            (
+                "openssl-3.0.12-dev",
+                "openssl-3.0.12",
+                "openssl",
+                Some("3.0.12"),
+                Some("dev"),
+            ),
+            (
+                "hello-2.12.1.drv",
+                "hello-2.12.1",
+                "hello",
+                Some("2.12.1"),
+                None,
+            ),
+            ("hello.drv", "hello", "hello", None, None),
+            ("foo-man", "foo-man", "foo-man", None, None),
+            ("foo-bar-dev", "foo-bar-dev", "foo-bar-dev", None, None),
+            ("hello.sh", "hello.sh", "hello.sh", None, None),
+            (
+                "foo-bar-1.2.3",
+                "foo-bar-1.2.3",
+                "foo-bar",
+                Some("1.2.3"),
+                None,
+            ),
+        ] {
+            let path = parse(name).unwrap();
+            assert_eq!(path.drvname(), drvname, "{}", name);
+            assert_eq!(path.pname(), pname, "{}", name);
+            assert_eq!(path.version(), version, "{}", name);
+            assert_eq!(path.output(), output, "{}", name);
+            assert_eq!(path.isderivation(), name.ends_with(".drv"), "{}", name);
+        }
+    }
+}
@@ -1,42 +1,63 @@
-use crate::HOME;
-use anyhow::{Context, Result};
+use crate::{
+    cache::profile::{profiledir, profileid},
+    context::datahome,
+    Error, Result,
+};
 use std::{
+    collections::HashMap,
     fs::{self, File},
-    path::Path, io::{Read, Write},
+    io::{Read, Write},
+    path::Path,
+    process::{Command, Output},
 };
 
-/// Refreshes desktop icons for applications installed with Nix
-pub fn refreshicons() -> Result<()> {
-    let desktoppath = &format!("{}/.local/share/applications", &*HOME);
-    let iconpath = &format!("{}/.local/share/icons/nixrefresh.png", &*HOME);
+/// Refreshes desktop icons for applications installed with Nix in `profile`,
+/// or in the [default profile](crate::cache::profile::defaultprofile) of the user if it is `None`.
+/// Entries of other profiles are kept, so this can be called for each profile.
+pub fn refreshicons(profile: Option<&Path>) -> Result<()> {
+    let id =
+        profileid(profile)?.ok_or_else(|| Error::Config(String::from("No nix profile found")))?;
+    let marker = format!("# Nix Profile: {}", id.display());
+    let isdefault = profileid(None)?.as_ref() == Some(&id);
+    let datahome = datahome()?;
+    let desktoppath = &format!("{}/applications", datahome);
+    let iconpath = &format!("{}/icons/nixrefresh.png", datahome);
     fs::create_dir_all(desktoppath)?;
-    fs::create_dir_all(&format!("{}/.local/share/icons", &*HOME))?;
+    fs::create_dir_all(format!("{}/icons", datahome))?;
 
-    // Clean up old files
+    // Clean up old files of this profile. Files without a profile are from the default profile.
     for filename in (fs::read_dir(desktoppath)?).flatten() {
-        if filename.file_type()?.is_file() && fs::read_to_string(filename.path())?.lines().next() == Some("# Nix Desktop Entry") {
+        if !filename.file_type()?.is_file() {
+            continue;
+        }
+        let contents = fs::read_to_string(filename.path())?;
+        let mut lines = contents.lines();
+        if lines.next() != Some("# Nix Desktop Entry") {
+            continue;
+        }
+        let ours = match lines.next().filter(|l| l.starts_with("# Nix Profile: ")) {
+            Some(line) => line == marker,
+            None => isdefault,
+        };
+        if ours {
             fs::remove_file(filename.path())?;
         }
     }
 
-    for filename in
-        (fs::read_dir(&format!("{}/.nix-profile/share/applications", &*HOME))?).flatten()
-    {
-        let filepath = filename.path().to_str().context("file path")?.to_string();
-        let localpath = format!(
-            "{}/{}",
-            desktoppath,
-            filename.file_name().to_str().context("file name")?
-        );
+    let profile =
+        profiledir(profile)?.ok_or_else(|| Error::Config(String::from("No nix profile found")))?;
+    for filename in (fs::read_dir(profile.join("share/applications"))?).flatten() {
+        let filepath = filename.path();
+        let localpath = format!("{}/{}", desktoppath, filename.file_name().to_string_lossy());
         if Path::new(&localpath).exists() {
             fs::remove_file(&localpath)?;
         }
         fs::copy(&filepath, &localpath)?;
-        // Write "# Nix Desktop Entry" to the top of the file
+        // Write "# Nix Desktop Entry" and the profile to the top of the file
         let mut file = File::open(&localpath)?;
         let mut contents = String::new();
         file.read_to_string(&mut contents)?;
-        contents = format!("# Nix Desktop Entry\n{}", contents);
+        contents = format!("# Nix Desktop Entry\n{}\n{}", marker, contents);
         fs::remove_file(&localpath)?;
         let mut file = File::create(&localpath)?;
         file.write_all(contents.as_bytes())?;
@@ -55,3 +76,38 @@ pub fn refreshicons() -> Result<()> {
 
     Ok(())
 }
+
+/// Runs a command and returns its output.
+/// Errors with [Error::Command] if the command can't be started, such as when it isn't installed.
+pub(crate) fn output(cmd: &mut Command) -> Result<Output> {
+    cmd.output().map_err(|source| Error::Command {
+        command: cmd.get_program().to_string_lossy().to_string(),
+        source,
+    })
+}
+
+/// Runs a command like [output()], without blocking the async runtime while it runs.
+pub(crate) async fn outputasync(cmd: &mut tokio::process::Command) -> Result<Output> {
+    cmd.output().await.map_err(|source| Error::Command {
+        command: cmd.as_std().get_program().to_string_lossy().to_string(),
+        source,
+    })
+}
+
+/// Returns the output of `nixos-version --json`.
+/// Errors with [Error::NotNixos] if the system isn't NixOS.
+pub(crate) fn nixosversion() -> Result<HashMap<String, String>> {
+    if !Path::new("/etc/NIXOS").exists() {
+        return Err(Error::NotNixos);
+    }
+    let versionout = output(Command::new("nixos-version").arg("--json"))?;
+    Ok(serde_json::from_slice(&versionout.stdout)?)
+}
+
+/// Returns the release of the running NixOS system, such as `23.05`.
+pub(crate) fn nixosrelease(version: &HashMap<String, String>) -> Result<&str> {
+    version
+        .get("nixosVersion")
+        .and_then(|v| v.get(0..5))
+        .ok_or_else(|| Error::Parse(String::from("No NixOS version found")))
+}
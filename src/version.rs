@@ -0,0 +1,315 @@
+use std::{cmp::Ordering, convert::Infallible, fmt, str::FromStr};
+
+/// Kind of change between two versions of a package,
+/// decided by the first component that differs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Bump {
+    /// The first component changed, such as `1.4` to `2.0`.
+    Major,
+    /// The second component changed, such as `1.4` to `1.5`.
+    Minor,
+    /// A later component changed, such as `1.4.1` to `1.4.2`.
+    /// This includes the release of a pre-release, such as `1.4pre1` to `1.4`,
+    /// as only the components after the release number differ.
+    Patch,
+    /// The new version is a pre-release, such as `1.4` to `1.5pre1` or `2.0-rc1`.
+    PreRelease,
+    /// The new version is older.
+    Downgrade,
+}
+
+/// A package version ordered like Nix orders them with `builtins.compareVersions`,
+/// so that `1.10` is newer than `1.9` and `1.0pre1` is older than `1.0`.
+///
+/// # Example
+/// ```
+/// use nix_data::version::{Bump, NixVersion};
+///
+/// let mut versions = ["1.10", "1.9", "1.0pre1", "1.0", "1.0a"]
+///     .iter()
+///     .map(|v| NixVersion::from(*v))
+///     .collect::<Vec<_>>();
+/// versions.sort();
+/// assert_eq!(
+///     versions.iter().map(|v| v.as_str()).collect::<Vec<_>>(),
+///     ["1.0pre1", "1.0", "1.0a", "1.9", "1.10"]
+/// );
+/// assert_eq!(NixVersion::from("1.9").bump(&"1.10".into()), Some(Bump::Minor));
+/// ```
+#[derive(Debug, Clone)]
+pub struct NixVersion(String);
+
+/// Components that mark a pre-release version.
+const PRERELEASE: [&str; 5] = ["pre", "rc", "alpha", "beta", "dev"];
+
+impl NixVersion {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Splits the version into components like Nix does.
+    /// Components are runs of digits or runs of other characters, separated by `.` and `-`.
+    pub fn components(&self) -> Vec<&str> {
+        components(&self.0)
+    }
+
+    /// Whether this is a pre-release version, such as `1.0pre1` or `2.0-rc1`.
+    pub fn isprerelease(&self) -> bool {
+        self.components()
+            .iter()
+            .any(|c| PRERELEASE.contains(&c.to_lowercase().as_str()))
+    }
+
+    /// Classifies the change from this version to `new`.
+    /// Returns `None` if both versions are equal.
+    ///
+    /// Upgrades to a pre-release are always [Bump::PreRelease]. Other upgrades are classified by the first differing component,
+    /// so releasing a pre-release is a [Bump::Patch] (`1.0pre1` to `1.0`) unless the release number changes too (`1.0pre1` to `1.1`).
+    pub fn bump(&self, new: &NixVersion) -> Option<Bump> {
+        match self.cmp(new) {
+            Ordering::Equal => None,
+            Ordering::Greater => Some(Bump::Downgrade),
+            Ordering::Less if new.isprerelease() => Some(Bump::PreRelease),
+            Ordering::Less => {
+                let old = self.components();
+                let new = new.components();
+                let changed = (0..old.len().max(new.len()))
+                    .find(|i| old.get(*i) != new.get(*i))
+                    .unwrap_or_default();
+                Some(match changed {
+                    0 => Bump::Major,
+                    1 => Bump::Minor,
+                    _ => Bump::Patch,
+                })
+            }
+        }
+    }
+}
+
+/// Compares two versions with the semantics of `builtins.compareVersions`.
+pub fn compareversions(v1: &str, v2: &str) -> Ordering {
+    let mut c1 = Components(v1);
+    let mut c2 = Components(v2);
+    loop {
+        match (c1.next(), c2.next()) {
+            (None, None) => return Ordering::Equal,
+            (a, b) => {
+                let a = a.unwrap_or_default();
+                let b = b.unwrap_or_default();
+                if componentslt(a, b) {
+                    return Ordering::Less;
+                } else if componentslt(b, a) {
+                    return Ordering::Greater;
+                }
+            }
+        }
+    }
+}
+
+/// Classifies the change from version `old` to `new`. Returns `None` if both are equal.
+pub fn bump(old: &str, new: &str) -> Option<Bump> {
+    NixVersion::from(old).bump(&NixVersion::from(new))
+}
+
+/// Splits a derivation name such as `hello-2.12.1` into its name and version like Nix does,
+/// at the first `-` not followed by a letter. The version is empty if there is none.
+pub fn parsedrvname(drvname: &str) -> (&str, &str) {
+    drvname
+        .char_indices()
+        .find(|(i, c)| {
+            *c == '-'
+                && drvname[i + 1..]
+                    .chars()
+                    .next()
+                    .is_some_and(|next| !next.is_ascii_alphabetic())
+        })
+        .map(|(i, _)| (&drvname[..i], &drvname[i + 1..]))
+        .unwrap_or((drvname, ""))
+}
+
+fn components(version: &str) -> Vec<&str> {
+    Components(version).collect()
+}
+
+/// Iterator over the components of a version.
+struct Components<'a>(&'a str);
+
+impl<'a> Iterator for Components<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        let rest = self.0.trim_start_matches(['.', '-']);
+        if rest.is_empty() {
+            self.0 = rest;
+            return None;
+        }
+        let digits = rest.starts_with(|c: char| c.is_ascii_digit());
+        let end = rest
+            .find(|c: char| {
+                if digits {
+                    !c.is_ascii_digit()
+                } else {
+                    c.is_ascii_digit() || c == '.' || c == '-'
+                }
+            })
+            .unwrap_or(rest.len());
+        self.0 = &rest[end..];
+        Some(&rest[..end])
+    }
+}
+
+/// Port of `componentsLT` from Nix.
+fn componentslt(c1: &str, c2: &str) -> bool {
+    // Nix parses numbers as `int`, longer ones compare as strings
+    let n1 = c1.parse::<i32>().ok();
+    let n2 = c2.parse::<i32>().ok();
+    match (n1, n2) {
+        (Some(n1), Some(n2)) => n1 < n2,
+        _ if c1.is_empty() && n2.is_some() => true,
+        _ if c1 == "pre" && c2 != "pre" => true,
+        _ if c2 == "pre" => false,
+        // Assume that `2.3a` < `2.3.1`
+        (_, Some(_)) => true,
+        (Some(_), _) => false,
+        _ => c1 < c2,
+    }
+}
+
+impl Ord for NixVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compareversions(&self.0, &other.0)
+    }
+}
+
+impl PartialOrd for NixVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Versions are equal if Nix considers them equal, such as `1.0` and `1-0`.
+impl PartialEq for NixVersion {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for NixVersion {}
+
+impl fmt::Display for NixVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<&str> for NixVersion {
+    fn from(version: &str) -> Self {
+        NixVersion(version.to_string())
+    }
+}
+
+impl From<String> for NixVersion {
+    fn from(version: String) -> Self {
+        NixVersion(version)
+    }
+}
+
+impl FromStr for NixVersion {
+    type Err = Infallible;
+
+    fn from_str(version: &str) -> Result<Self, Self::Err> {
+        Ok(NixVersion::from(version))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comparesversions() {
+        // From tests/functional/lang/eval-okay-versions.nix in Nix
+        let cases = [
+            ("1.0", "2.3", Ordering::Less),
+            ("2.1", "2.3", Ordering::Less),
+            ("2.3", "2.3", Ordering::Equal),
+            ("2.5", "2.3", Ordering::Greater),
+            ("3.1", "2.3", Ordering::Greater),
+            ("2.3.1", "2.3", Ordering::Greater),
+            ("2.3.1", "2.3a", Ordering::Greater),
+            ("2.3pre1", "2.3", Ordering::Less),
+            ("2.3pre3", "2.3pre12", Ordering::Less),
+            ("2.3a", "2.3c", Ordering::Less),
+            ("2.3pre1", "2.3c", Ordering::Less),
+            ("2.3pre1", "2.3q", Ordering::Less),
+            // Missing and empty components
+            ("1.0", "1.0.0", Ordering::Less),
+            ("1..0", "1.0", Ordering::Equal),
+            ("1.0-", "1-0", Ordering::Equal),
+            ("", "0", Ordering::Less),
+            ("", "a", Ordering::Less),
+            ("", "", Ordering::Equal),
+            // Numbers are newer than letters, but `pre` is older than anything
+            ("1a", "1.0", Ordering::Less),
+            ("1.0rc1", "1.0.1", Ordering::Less),
+            ("1.0pre", "1.0rc", Ordering::Less),
+            ("1.0pre", "1.0", Ordering::Less),
+            ("1.0pre", "1.0pre", Ordering::Equal),
+            ("1.10", "1.9", Ordering::Greater),
+            // Numbers too large for an `int` are compared as strings, which are older than numbers
+            ("1.99999999999", "1.2", Ordering::Less),
+        ];
+        for (v1, v2, expected) in cases {
+            assert_eq!(compareversions(v1, v2), expected, "{} vs {}", v1, v2);
+            assert_eq!(
+                compareversions(v2, v1),
+                expected.reverse(),
+                "{} vs {}",
+                v2,
+                v1
+            );
+        }
+    }
+
+    #[test]
+    fn parsesdrvnames() {
+        // From tests/functional/lang/eval-okay-versions.nix in Nix
+        let cases = [
+            ("hello-1.0.2", ("hello", "1.0.2")),
+            ("hello", ("hello", "")),
+            ("915resolution-0.5.2", ("915resolution", "0.5.2")),
+            ("xf86-video-i810-1.7.4", ("xf86-video-i810", "1.7.4")),
+            (
+                "name-that-ends-with-dash--1.0",
+                ("name-that-ends-with-dash", "-1.0"),
+            ),
+            ("foo-bar-1.2.3", ("foo-bar", "1.2.3")),
+            ("foo-1-bar", ("foo", "1-bar")),
+            ("foo-", ("foo-", "")),
+        ];
+        for (drvname, expected) in cases {
+            assert_eq!(parsedrvname(drvname), expected, "{}", drvname);
+        }
+    }
+
+    #[test]
+    fn classifiesbumps() {
+        let cases = [
+            ("1.4", "2.0", Some(Bump::Major)),
+            ("1.4", "1.5", Some(Bump::Minor)),
+            ("1.9", "1.10", Some(Bump::Minor)),
+            ("1.4.1", "1.4.2", Some(Bump::Patch)),
+            ("1.4", "1.4.1", Some(Bump::Patch)),
+            ("1.4", "1.5pre1", Some(Bump::PreRelease)),
+            ("1.4", "2.0-rc1", Some(Bump::PreRelease)),
+            ("1.0pre1", "1.0", Some(Bump::Patch)),
+            ("1.0pre1", "1.1", Some(Bump::Minor)),
+            ("1.0rc1", "2.0", Some(Bump::Major)),
+            ("2.0", "1.4", Some(Bump::Downgrade)),
+            ("1.0", "1-0", None),
+        ];
+        for (old, new, expected) in cases {
+            assert_eq!(bump(old, new), expected, "{} -> {}", old, new);
+        }
+    }
+}